@@ -29,6 +29,7 @@ return {
         "sqlls",
         "jsonls",
         "yamlls",
+        "taplo",
         "rnix",
         "gopls",
         "jinja_lsp",
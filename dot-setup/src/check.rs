@@ -0,0 +1,538 @@
+//! Validation of config.toml and host overlays that points at the offending
+//! line, for `dot-setup check` and for [`load_config`](crate::config::load_config).
+
+use crate::config::{Config, Overlay, KINDS, MAX_RETRIES, MAX_RETRY_BACKOFF};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+use toml::Spanned;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// Where in which file a problem is.
+#[derive(Serialize, Clone, Debug)]
+pub struct Location {
+    pub file: String,
+    /// 1-based, like editors count.
+    pub line: usize,
+    pub column: usize,
+    /// The text of that line, shown under the message.
+    #[serde(skip)]
+    pub source_line: String,
+    /// How many characters of `source_line` to underline.
+    #[serde(skip)]
+    pub width: usize,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Problem {
+    pub severity: Severity,
+    pub message: String,
+    #[serde(flatten)]
+    pub location: Option<Location>,
+}
+
+impl Problem {
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}", severity, self.message)?;
+        let Some(location) = &self.location else { return Ok(()) };
+        let gutter = " ".repeat(location.line.to_string().len());
+        write!(f, "\n{}--> {}:{}:{}", gutter, location.file, location.line, location.column)?;
+        write!(f, "\n{} |", gutter)?;
+        write!(f, "\n{} | {}", location.line, location.source_line)?;
+        write!(
+            f,
+            "\n{} | {}{}",
+            gutter,
+            " ".repeat(location.column - 1),
+            "^".repeat(location.width.max(1))
+        )
+    }
+}
+
+/// Errors that stop a config from loading.
+#[derive(Debug)]
+pub struct Invalid {
+    pub problems: Vec<Problem>,
+}
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for problem in &self.problems {
+            writeln!(f, "{}\n", problem)?;
+        }
+        match self.problems.len() {
+            1 => write!(f, "the config has 1 error"),
+            n => write!(f, "the config has {} errors", n),
+        }
+    }
+}
+
+impl std::error::Error for Invalid {}
+
+/// Fails with the errors among `problems`, ignoring warnings.
+pub fn ensure_valid(problems: Vec<Problem>) -> Result<(), Invalid> {
+    let errors: Vec<Problem> = problems.into_iter().filter(Problem::is_error).collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(Invalid { problems: errors })
+    }
+}
+
+/// One file being checked: its display name and contents.
+struct Source<'a> {
+    file: &'a str,
+    content: &'a str,
+    problems: Vec<Problem>,
+}
+
+impl Source<'_> {
+    /// The problems found, in file order.
+    fn finish(mut self) -> Vec<Problem> {
+        self.problems.sort_by_key(|problem| problem.location.as_ref().map(|l| (l.line, l.column)));
+        self.problems
+    }
+
+    fn locate(&self, span: Range<usize>) -> Location {
+        let start = span.start.min(self.content.len());
+        let line_start = self.content[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = self.content[start..].find('\n').map_or(self.content.len(), |i| start + i);
+        let source_line = &self.content[line_start..line_end];
+        let column = self.content[line_start..start].chars().count() + 1;
+        let end = span.end.clamp(start, line_end);
+        Location {
+            file: self.file.to_string(),
+            line: self.content[..start].matches('\n').count() + 1,
+            column,
+            source_line: source_line.to_string(),
+            width: self.content[start..end].chars().count(),
+        }
+    }
+
+    fn report(&mut self, severity: Severity, span: Option<Range<usize>>, message: String) {
+        let location = span.map(|span| self.locate(span));
+        self.problems.push(Problem { severity, message, location });
+    }
+
+    fn error<T>(&mut self, at: &Spanned<T>, message: String) {
+        self.report(Severity::Error, Some(at.span()), message);
+    }
+
+    fn warning<T>(&mut self, at: &Spanned<T>, message: String) {
+        self.report(Severity::Warning, Some(at.span()), message);
+    }
+
+    fn toml_error(&mut self, err: toml::de::Error) {
+        self.report(Severity::Error, err.span(), err.message().trim_end().to_string());
+    }
+}
+
+/// The parts of a task that are checked, with their positions in the file.
+/// Everything else is left to the real [`Config`] parse.
+#[derive(Deserialize)]
+struct RawTask {
+    id: Option<Spanned<String>>,
+    kind: Option<Spanned<String>>,
+    #[serde(default)]
+    depends_on: Vec<Spanned<String>>,
+    packages: Option<Spanned<Vec<Spanned<String>>>>,
+    apps: Option<Spanned<Vec<Spanned<String>>>>,
+    repositories: Option<Spanned<Vec<RawAptRepository>>>,
+    #[serde(default)]
+    release_packages: Vec<Spanned<String>>,
+    #[serde(default)]
+    repo_files: Vec<Spanned<String>>,
+    #[serde(default)]
+    bootstrap: Vec<RawBootstrap>,
+    remote_url: Option<Spanned<String>>,
+    install_script: Option<Spanned<String>>,
+    timeout: Option<Spanned<u64>>,
+    retries: Option<Spanned<u64>>,
+    retry_backoff: Option<Spanned<u64>>,
+}
+
+#[derive(Deserialize)]
+struct RawAptRepository {
+    uri: Option<Spanned<String>>,
+    key_url: Option<Spanned<String>>,
+}
+
+#[derive(Deserialize)]
+struct RawBootstrap {
+    baseurl: Option<Spanned<String>>,
+}
+
+#[derive(Deserialize)]
+struct RawOverlay {
+    #[serde(default)]
+    remove_tasks: Vec<String>,
+    #[serde(default)]
+    tasks: Vec<RawTask>,
+    #[serde(default)]
+    modify: BTreeMap<String, RawChanges>,
+}
+
+#[derive(Deserialize)]
+struct RawChanges {
+    #[serde(default)]
+    add: Vec<Spanned<String>>,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct RawConfig {
+    #[serde(default)]
+    tasks: Vec<RawTask>,
+    #[serde(default)]
+    profiles: BTreeMap<String, RawOverlay>,
+}
+
+/// Checks a config.toml. `file` names it in the problems.
+pub fn check_config(file: &str, content: &str) -> Vec<Problem> {
+    let mut source = Source { file, content, problems: Vec::new() };
+    let raw: RawConfig = match toml::from_str(content) {
+        Ok(raw) => raw,
+        Err(err) => {
+            source.toml_error(err);
+            return source.finish();
+        }
+    };
+
+    let mut ids: Vec<&str> = raw.tasks.iter().filter_map(|task| task.id.as_ref()).map(|id| id.get_ref().as_str()).collect();
+    for overlay in raw.profiles.values() {
+        ids.extend(overlay.tasks.iter().filter_map(|task| task.id.as_ref()).map(|id| id.get_ref().as_str()));
+    }
+
+    check_tasks(&mut source, &raw.tasks, Some(&ids));
+    for overlay in raw.profiles.values() {
+        check_tasks(&mut source, &overlay.tasks, Some(&ids));
+    }
+
+    if !source.problems.iter().any(Problem::is_error) {
+        if let Err(err) = toml::from_str::<Config>(content) {
+            source.toml_error(err);
+        }
+    }
+    source.finish()
+}
+
+/// Checks a `hosts/<hostname>.toml` overlay on its own; references to tasks
+/// of config.toml are checked once it is applied.
+pub fn check_overlay(file: &str, content: &str) -> Vec<Problem> {
+    let mut source = Source { file, content, problems: Vec::new() };
+    match toml::from_str::<RawOverlay>(content) {
+        Ok(raw) => check_tasks(&mut source, &raw.tasks, None),
+        Err(err) => source.toml_error(err),
+    }
+    if !source.problems.iter().any(Problem::is_error) {
+        if let Err(err) = toml::from_str::<Overlay>(content) {
+            source.toml_error(err);
+        }
+    }
+    source.finish()
+}
+
+/// Per-task checks. `ids` are the task ids `depends_on` may name, when known.
+fn check_tasks(source: &mut Source, tasks: &[RawTask], ids: Option<&[&str]>) {
+    let mut seen = HashSet::new();
+    for task in tasks {
+        if let Some(id) = &task.id {
+            if !seen.insert(id.get_ref()) {
+                source.error(id, format!("task id `{}` is used twice", id.get_ref()));
+            }
+        }
+        if let Some(ids) = ids {
+            for dep in &task.depends_on {
+                if !ids.contains(&dep.get_ref().as_str()) {
+                    source.error(dep, format!("depends on unknown task `{}`", dep.get_ref()));
+                }
+            }
+        }
+
+        let name = task.id.as_ref().map_or("?", |id| id.get_ref().as_str());
+        if let Some(timeout) = task.timeout.as_ref().filter(|timeout| *timeout.get_ref() == 0) {
+            source.error(timeout, format!("`timeout` of task `{}` must be at least 1 second", name));
+        }
+        if let Some(retries) = task.retries.as_ref().filter(|retries| *retries.get_ref() > MAX_RETRIES) {
+            source.error(retries, format!("`retries` of task `{}` is over the maximum of {}", name, MAX_RETRIES));
+        }
+        if let Some(backoff) = task.retry_backoff.as_ref().filter(|backoff| *backoff.get_ref() > MAX_RETRY_BACKOFF) {
+            source.error(
+                backoff,
+                format!("`retry_backoff` of task `{}` is over the maximum of {}s", name, MAX_RETRY_BACKOFF),
+            );
+        }
+
+        let Some(kind) = &task.kind else { continue };
+        if !KINDS.contains(&kind.get_ref().as_str()) {
+            source.error(kind, format!("unknown task kind `{}` (expected one of: {})", kind.get_ref(), KINDS.join(", ")));
+            continue;
+        }
+
+        let list = match kind.get_ref().as_str() {
+            "dnf" | "apt" | "brew" | "cargo" => Some(("packages", task.packages.as_ref().map(|l| (l.span(), l.get_ref().is_empty())))),
+            "flatpak" => Some(("apps", task.apps.as_ref().map(|l| (l.span(), l.get_ref().is_empty())))),
+            "apt-repos" => Some(("repositories", task.repositories.as_ref().map(|l| (l.span(), l.get_ref().is_empty())))),
+            _ => None,
+        };
+        if let Some((key, Some((span, true)))) = list {
+            source.report(Severity::Error, Some(span), format!("`{}` of task `{}` is empty", key, name));
+        }
+        if kind.get_ref() == "dnf-repos" && task.release_packages.is_empty() && task.repo_files.is_empty() && task.bootstrap.is_empty() {
+            source.error(kind, format!("task `{}` adds no repositories", name));
+        }
+        if kind.get_ref() == "brew" && task.install_script.is_none() {
+            source.error(kind, format!("brew task `{}` is missing `install_script`", name));
+        }
+
+        let mut urls: Vec<&Spanned<String>> = Vec::new();
+        urls.extend(&task.install_script);
+        urls.extend(&task.remote_url);
+        urls.extend(&task.repo_files);
+        urls.extend(task.bootstrap.iter().filter_map(|repo| repo.baseurl.as_ref()));
+        for repo in task.repositories.iter().flat_map(|repos| repos.get_ref()) {
+            urls.extend(&repo.uri);
+            urls.extend(&repo.key_url);
+        }
+        // Packages may be given as URLs to an RPM or a .deb.
+        let packages = task.packages.iter().flat_map(|l| l.get_ref()).chain(&task.release_packages);
+        urls.extend(packages.filter(|pkg| pkg.get_ref().contains("://")));
+        for url in urls {
+            if let Some(problem) = url_problem(url.get_ref()) {
+                source.error(url, format!("malformed URL `{}`: {}", url.get_ref(), problem));
+            }
+        }
+    }
+}
+
+/// A task of the merged list: its packages or apps, each with the index of
+/// the file that listed it.
+struct MergedTask<'a> {
+    id: &'a str,
+    kind: &'a str,
+    items: Vec<(usize, &'a Spanned<String>)>,
+}
+
+impl<'a> MergedTask<'a> {
+    fn new(file: usize, task: &'a RawTask) -> Option<Self> {
+        let (id, kind) = (task.id.as_ref()?.get_ref(), task.kind.as_ref()?.get_ref());
+        let items = task.packages.as_ref().or(task.apps.as_ref()).map_or(&[][..], |items| items.get_ref());
+        Some(MergedTask { id, kind, items: items.iter().map(|item| (file, item)).collect() })
+    }
+}
+
+/// Layers the items of `overlay`, from file `file`, onto `tasks` the way
+/// [`Config::apply`] does.
+fn merge<'a>(tasks: &mut Vec<MergedTask<'a>>, file: usize, overlay: &'a RawOverlay) {
+    tasks.retain(|task| !overlay.remove_tasks.iter().any(|id| id == task.id));
+    for task in overlay.tasks.iter().filter_map(|task| MergedTask::new(file, task)) {
+        match tasks.iter_mut().find(|existing| existing.id == task.id) {
+            Some(existing) => *existing = task,
+            None => tasks.push(task),
+        }
+    }
+    for (id, changes) in &overlay.modify {
+        let Some(task) = tasks.iter_mut().find(|task| task.id == id) else { continue };
+        task.items.retain(|(_, item)| !changes.remove.contains(item.get_ref()));
+        task.items.extend(changes.add.iter().map(|item| (file, item)));
+    }
+}
+
+/// Warns about a package listed twice in one task, or in two tasks of the
+/// same kind, once `profile` and the host overlay `host` are applied to
+/// `config`. Each file is given as its display name and contents; the
+/// warning points at the second listing and names the first.
+pub fn check_duplicate_items(config: (&str, &str), profile: Option<&str>, host: Option<(&str, &str)>) -> Vec<Problem> {
+    let Ok(raw) = toml::from_str::<RawConfig>(config.1) else { return Vec::new() };
+    let host_raw = match host.map(|(_, content)| toml::from_str::<RawOverlay>(content)) {
+        Some(Ok(raw)) => Some(raw),
+        Some(Err(_)) => return Vec::new(),
+        None => None,
+    };
+
+    let mut sources = vec![Source { file: config.0, content: config.1, problems: Vec::new() }];
+    let mut tasks: Vec<MergedTask> = raw.tasks.iter().filter_map(|task| MergedTask::new(0, task)).collect();
+    if let Some(overlay) = profile.and_then(|name| raw.profiles.get(name)) {
+        merge(&mut tasks, 0, overlay);
+    }
+    if let (Some((file, content)), Some(overlay)) = (host, &host_raw) {
+        sources.push(Source { file, content, problems: Vec::new() });
+        merge(&mut tasks, 1, overlay);
+    }
+
+    let mut seen: HashMap<(&str, &str), (&str, usize, &Spanned<String>)> = HashMap::new();
+    for task in &tasks {
+        for &(file, item) in &task.items {
+            let Some((first, first_file, first_item)) = seen.insert((task.kind, item.get_ref()), (task.id, file, item))
+            else {
+                continue;
+            };
+            let at = sources[first_file].locate(first_item.span());
+            let at = format!("{}:{}:{}", at.file, at.line, at.column);
+            let message = if first == task.id {
+                format!("`{}` is listed twice in task `{}` (first at {})", item.get_ref(), first, at)
+            } else {
+                format!("`{}` is already installed by task `{}` (listed at {})", item.get_ref(), first, at)
+            };
+            sources[file].warning(item, message);
+        }
+    }
+    sources.into_iter().flat_map(Source::finish).collect()
+}
+
+/// Why `url` isn't usable, if it isn't. `$(...)` shell substitutions, as in
+/// `$(rpm -E %fedora)`, are allowed anywhere.
+fn url_problem(url: &str) -> Option<String> {
+    let Some((scheme, rest)) = url.split_once("://") else { return Some("missing `scheme://`".to_string()) };
+    if !matches!(scheme, "http" | "https" | "ftp" | "file") {
+        return Some(format!("unsupported scheme `{}`", scheme));
+    }
+    let host = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    if scheme != "file" && host.is_empty() {
+        return Some("no host".to_string());
+    }
+    let valid = |c: char| c.is_ascii_alphanumeric() || ".-_:@[]".contains(c);
+    // A host built from `$VAR` or `$(...)` is only known when the shell runs.
+    if !host.contains('$') && !host.chars().all(valid) {
+        return Some(format!("invalid host `{}`", host));
+    }
+
+    let mut rest = url;
+    while let Some(start) = rest.find("$(") {
+        if rest[..start].contains(char::is_whitespace) {
+            break;
+        }
+        rest = rest[start..].find(')').map_or("", |end| &rest[start + end + 1..]);
+    }
+    rest.contains(char::is_whitespace).then(|| "contains whitespace".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(problems: &[Problem]) -> Vec<&str> {
+        problems.iter().map(|problem| problem.message.as_str()).collect()
+    }
+
+    #[test]
+    fn locate_counts_lines_and_characters() {
+        let content = "a = 1\nname = \"é ✓\"\nb = 2\n";
+        let source = Source { file: "config.toml", content, problems: Vec::new() };
+        let start = content.find('✓').unwrap();
+        let location = source.locate(start..start + '✓'.len_utf8());
+        assert_eq!((location.line, location.column, location.width), (2, 11, 1));
+        assert_eq!(location.source_line, "name = \"é ✓\"");
+
+        let location = source.locate(0..1);
+        assert_eq!((location.line, location.column), (1, 1));
+        // A span past the end, as toml reports for a missing value at EOF.
+        assert_eq!(source.locate(content.len()..content.len() + 5).line, 4);
+    }
+
+    const CONFIG: &str = r#"
+[[tasks]]
+id = "apt-base"
+name = "Base"
+kind = "apt"
+packages = ["vim", "curl"]
+
+[profiles.dev]
+[[profiles.dev.tasks]]
+id = "apt-dev"
+name = "Dev"
+kind = "apt"
+packages = ["git"]
+"#;
+
+    #[test]
+    fn duplicates_across_profile_and_host_overlay() {
+        let host = "[modify.apt-base]\nadd = [\"git\"]\n";
+        assert!(check_duplicate_items(("config.toml", CONFIG), None, Some(("hosts/box.toml", host))).is_empty());
+        assert!(check_duplicate_items(("config.toml", CONFIG), Some("dev"), None).is_empty());
+
+        let problems = check_duplicate_items(("config.toml", CONFIG), Some("dev"), Some(("hosts/box.toml", host)));
+        // Tasks are walked in list order, so apt-dev's listing is the second.
+        assert_eq!(messages(&problems), ["`git` is already installed by task `apt-base` (listed at hosts/box.toml:2:8)"]);
+        let location = problems[0].location.as_ref().unwrap();
+        assert_eq!((location.file.as_str(), location.line, location.column), ("config.toml", 13, 13));
+        assert_eq!(problems[0].severity, Severity::Warning);
+    }
+
+    #[test]
+    fn duplicates_within_a_task_and_removed_items() {
+        let host = "[modify.apt-base]\nadd = [\"vim\"]\nremove = [\"curl\"]\n\n[modify.apt-dev]\nadd = [\"curl\"]\n";
+        let problems = check_duplicate_items(("config.toml", CONFIG), Some("dev"), Some(("hosts/box.toml", host)));
+        // `curl` was removed from apt-base first, so apt-dev may have it.
+        assert_eq!(messages(&problems), ["`vim` is listed twice in task `apt-base` (first at config.toml:6:13)"]);
+    }
+
+    #[test]
+    fn urls() {
+        for url in [
+            "https://download.docker.com/linux/fedora/docker-ce.repo",
+            "https://mirrors.rpmfusion.org/free/fedora/rpmfusion-free-release-$(rpm -E %fedora).noarch.rpm",
+            "https://repos.fyralabs.com/terra$releasever",
+            "https://$MIRROR/fedora/$basearch/",
+            "https://$(hostname -d)/repo",
+            "file:///etc/pki/key.gpg",
+        ] {
+            assert_eq!(url_problem(url), None, "{}", url);
+        }
+        for (url, problem) in [
+            ("download.docker.com/gpg", "missing `scheme://`"),
+            ("gopher://example.com", "unsupported scheme `gopher`"),
+            ("https:///path", "no host"),
+            ("https://exa mple.com/", "invalid host `exa mple.com`"),
+            ("https://example.com/a b", "contains whitespace"),
+        ] {
+            assert_eq!(url_problem(url).as_deref(), Some(problem), "{}", url);
+        }
+    }
+
+    fn shell_task(extra: &str) -> String {
+        format!("[[tasks]]\nid = \"a\"\nname = \"A\"\nkind = \"shell\"\ncommand = \"true\"\n{}\n", extra)
+    }
+
+    #[test]
+    fn retry_settings_out_of_range() {
+        assert!(check_config("config.toml", &shell_task("timeout = 60\nretries = 10\nretry_backoff = 600")).is_empty());
+
+        let problems = check_config("config.toml", &shell_task("timeout = 0\nretries = 11\nretry_backoff = 601"));
+        assert_eq!(
+            messages(&problems),
+            [
+                "`timeout` of task `a` must be at least 1 second",
+                "`retries` of task `a` is over the maximum of 10",
+                "`retry_backoff` of task `a` is over the maximum of 600s",
+            ]
+        );
+        assert!(problems.iter().all(Problem::is_error));
+        assert!(ensure_valid(problems).is_err());
+    }
+
+    #[test]
+    fn unknown_dependencies_and_reused_ids() {
+        let content = format!("{}{}", shell_task("depends_on = [\"b\"]"), shell_task(""));
+        let problems = check_config("config.toml", &content);
+        assert_eq!(messages(&problems), ["depends on unknown task `b`", "task id `a` is used twice"]);
+    }
+}
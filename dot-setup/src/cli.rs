@@ -0,0 +1,705 @@
+use crate::check::{self, Invalid, Problem, Severity};
+use crate::config::{self, load_config, Config, ConfigSource, LoadOptions, Overlay, Resolution, Task, TaskKind};
+use crate::doctor;
+use crate::engine::{self, Reporter, RunOptions, StepStatus};
+use crate::privilege::{self, Sudo};
+use crate::runlog::{self, Elapsed, Resolved, RunLog};
+use crate::stow;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::Result;
+use schemars::generate::SchemaSettings;
+use serde_json::json;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use zeroize::Zeroizing;
+
+/// Exit code when at least one task failed, or `doctor` found a problem.
+const EXIT_TASK_FAILED: u8 = 1;
+/// Exit code for bad arguments or an unusable config.
+const EXIT_USAGE: u8 = 2;
+/// Exit code when sudo could not be authenticated.
+const EXIT_AUTH: u8 = 3;
+
+/// Sets up this machine and stows the dotfiles. Starts the TUI when no
+/// subcommand is given.
+#[derive(Parser)]
+#[command(name = "dot-setup", version)]
+pub struct Cli {
+    /// Print what the enabled tasks would do and exit (same as `plan`)
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Profile to apply on top of config.toml, e.g. `server`; defaults to
+    /// the host overlay's `profile` or `default_profile`
+    #[arg(long, global = true)]
+    pub profile: Option<String>,
+
+    /// Config file to use instead of searching `$DOT_SETUP_CONFIG`,
+    /// `$XDG_CONFIG_HOME/dot-setup/config.toml` and the repository
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+}
+
+impl Cli {
+    pub fn load_options(&self) -> LoadOptions {
+        LoadOptions { config: self.config.clone(), profile: self.profile.clone() }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Commands {
+    /// Run tasks without the TUI
+    Run(RunArgs),
+    /// List the tasks declared in config.toml
+    List(OutputArgs),
+    /// Print the commands each task would run without running anything
+    Plan(SelectArgs),
+    /// Undo a stow run: remove its links and put backed up files back
+    Restore(RestoreArgs),
+    /// Remove the dotfile links pointing into this repository
+    Unstow(UnstowArgs),
+    /// Check the tools the enabled tasks need and the state of every
+    /// dotfile link; exits with 1 when something is wrong
+    Doctor(OutputArgs),
+    /// Validate config.toml, its profiles and the host overlays; exits with
+    /// 2 when there are errors
+    Check(OutputArgs),
+    /// Print a JSON Schema for config.toml, for editors such as taplo
+    Schema(SchemaArgs),
+    /// List past runs, newest first
+    History(OutputArgs),
+    /// Print the log of a past run
+    Log(LogArgs),
+}
+
+#[derive(Args)]
+pub struct RunArgs {
+    #[command(flatten)]
+    select: SelectArgs,
+
+    /// Don't ask for confirmation before running
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Read the sudo password from the first line of stdin
+    #[arg(long)]
+    password_stdin: bool,
+
+    /// How many independent tasks may run at once; defaults to `jobs` in
+    /// config.toml
+    #[arg(short, long)]
+    jobs: Option<usize>,
+
+    /// Run only the steps the last run left failed or unfinished
+    #[arg(long, conflicts_with = "tasks")]
+    resume: bool,
+}
+
+#[derive(Args, Default)]
+pub struct SelectArgs {
+    /// Comma-separated task ids; defaults to the tasks enabled in config.toml
+    #[arg(long, value_delimiter = ',')]
+    tasks: Vec<String>,
+
+    /// What stow tasks do with existing files in the way of a link;
+    /// defaults to `on_conflict` in config.toml
+    #[arg(long, value_enum)]
+    on_conflict: Option<Resolution>,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Args)]
+pub struct UnstowArgs {
+    /// Comma-separated ids of the stow tasks to undo; defaults to all of them
+    #[arg(long, value_delimiter = ',')]
+    tasks: Vec<String>,
+
+    /// Print what would be removed without removing anything
+    #[arg(long)]
+    dry_run: bool,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Args)]
+pub struct RestoreArgs {
+    /// The run id printed by the stow task; lists the restorable runs when
+    /// omitted
+    run_id: Option<String>,
+}
+
+#[derive(Args)]
+pub struct LogArgs {
+    /// A run id from `dot-setup history`
+    #[arg(default_value = "last")]
+    run: String,
+
+    /// Print only the log of this task
+    #[arg(long)]
+    step: Option<String>,
+}
+
+#[derive(Args)]
+pub struct SchemaArgs {
+    /// Describe a `hosts/<hostname>.toml` overlay instead of config.toml
+    #[arg(long)]
+    overlay: bool,
+}
+
+#[derive(Args, Default)]
+pub struct OutputArgs {
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+}
+
+#[derive(Clone, Copy, PartialEq, Default, ValueEnum)]
+enum Format {
+    /// Human readable progress
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Runs a headless subcommand and maps its outcome to the process exit code.
+pub fn run(command: Commands, options: &LoadOptions) -> ExitCode {
+    let result = match command {
+        Commands::Run(args) => run_tasks(args, options),
+        Commands::List(args) => list(args, options),
+        Commands::Plan(args) => plan(args, options),
+        Commands::Restore(args) => restore(args),
+        Commands::Unstow(args) => unstow(args, options),
+        Commands::Doctor(args) => doctor(args, options),
+        Commands::Check(args) => check(args, options),
+        Commands::Schema(args) => schema(args),
+        Commands::History(args) => history(args),
+        Commands::Log(args) => log(args),
+    };
+    result.unwrap_or_else(|(code, err)| {
+        match err.downcast_ref::<Invalid>() {
+            Some(invalid) => eprintln!("{}", invalid),
+            None => eprintln!("error: {:#}", err),
+        }
+        ExitCode::from(code)
+    })
+}
+
+type CliResult = std::result::Result<ExitCode, (u8, color_eyre::Report)>;
+
+fn usage(err: color_eyre::Report) -> (u8, color_eyre::Report) {
+    (EXIT_USAGE, err)
+}
+
+fn list(args: OutputArgs, options: &LoadOptions) -> CliResult {
+    let config = load_config(options).map_err(usage)?;
+    for task in &config.tasks {
+        match args.format {
+            Format::Text => {
+                let checkbox = if task.enabled { "[x]" } else { "[ ]" };
+                println!("{} {:<12} {:<10} {}", checkbox, task.id, task.kind.name(), task.name);
+            }
+            Format::Json => println!(
+                "{}",
+                json!({ "id": task.id, "name": task.name, "kind": task.kind.name(), "enabled": task.enabled })
+            ),
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn plan(args: SelectArgs, options: &LoadOptions) -> CliResult {
+    let config = load_config(options).map_err(usage)?;
+    let on_failure = config.on_failure;
+    let tasks = select_tasks(config, &args).map_err(usage)?;
+    let reporter = reporter(args.output.format, &tasks, true, false);
+    let options = RunOptions { dry_run: true, jobs: 1, on_failure };
+    engine::run_tasks(&tasks, None, &options, &cancel_on_signal(), reporter);
+    Ok(ExitCode::SUCCESS)
+}
+
+fn doctor(args: OutputArgs, options: &LoadOptions) -> CliResult {
+    let config = load_config(options).map_err(usage)?;
+    let source = config.source.clone();
+    let tasks: Vec<Task> = config.tasks.into_iter().filter(|t| t.enabled).collect();
+    let mut problems = 0;
+
+    let tried = match &source {
+        ConfigSource::Embedded { tried } => tried.as_slice(),
+        ConfigSource::File { .. } => &[],
+    };
+    match args.format {
+        Format::Text => {
+            println!("Config: {}", source.describe());
+            for (origin, path) in tried {
+                println!("  tried {} ({})", path.display(), origin);
+            }
+            println!("Tools");
+        }
+        Format::Json => {
+            let tried: Vec<_> = tried.iter().map(|(origin, path)| json!({ "origin": origin, "path": path })).collect();
+            println!("{}", json!({ "check": "config", "problem": false, "config": source.describe(), "tried": tried }));
+        }
+    }
+    for check in doctor::check_tools(&tasks) {
+        problems += usize::from(check.is_problem());
+        match args.format {
+            Format::Text => {
+                let (icon, note) = match (&check.installed_by, check.found) {
+                    (_, true) => ("✓", String::new()),
+                    (Some(task), false) => ("!", format!("not found, installed by `{}`", task)),
+                    (None, false) => ("✗", format!("not found, needed by {}", check.needed_by.join(", "))),
+                };
+                println!("{}", format!("  {} {:<10} {}", icon, check.tool, note).trim_end());
+            }
+            Format::Json => println!("{}", json!({ "check": "tool", "problem": check.is_problem(), "tool": check })),
+        }
+    }
+
+    if args.format == Format::Text {
+        println!("Links");
+    }
+    for check in doctor::check_links(&tasks) {
+        match (args.format, check) {
+            (Format::Text, Ok(check)) => {
+                let icon = if check.is_problem() { "✗" } else { "✓" };
+                println!("  {} {} {}", icon, check.target.display(), check.state);
+                problems += usize::from(check.is_problem());
+            }
+            (Format::Json, Ok(check)) => {
+                println!("{}", json!({ "check": "link", "problem": check.is_problem(), "link": check }));
+                problems += usize::from(check.is_problem());
+            }
+            (Format::Text, Err(err)) => {
+                println!("  ✗ {}", err);
+                problems += 1;
+            }
+            (Format::Json, Err(err)) => {
+                println!("{}", json!({ "check": "link", "problem": true, "error": err }));
+                problems += 1;
+            }
+        }
+    }
+
+    if args.format == Format::Text {
+        match problems {
+            0 => println!("No problems found"),
+            1 => println!("1 problem found"),
+            n => println!("{} problems found", n),
+        }
+    }
+    if problems > 0 {
+        Ok(ExitCode::from(EXIT_TASK_FAILED))
+    } else {
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+fn check(args: OutputArgs, options: &LoadOptions) -> CliResult {
+    let source = config::find_config(options.config.as_deref()).map_err(|err| usage(err.into()))?;
+    let content = source.read().map_err(usage)?;
+    let file = source.to_string();
+    let mut problems = check::check_config(&file, &content);
+    let mut hosts = Vec::new();
+    for path in source.host_overlays() {
+        let content = fs::read_to_string(&path)
+            .wrap_err_with(|| format!("could not read {}", path.display()))
+            .map_err(usage)?;
+        let path = path.display().to_string();
+        problems.extend(check::check_overlay(&path, &content));
+        hosts.push((path, content));
+    }
+
+    // The rest only shows up once overlays are applied, so check the config
+    // with each profile and each host overlay.
+    let mut profiles = vec![options.profile.clone()];
+    if let Ok(config) = toml::from_str::<Config>(&content) {
+        let names = config.profiles.into_keys().map(Some);
+        profiles.extend(names.filter(|name| name != &options.profile));
+    }
+    let mut hosts: Vec<Option<(&str, &str)>> = hosts.iter().map(|(path, content)| Some((path.as_str(), content.as_str()))).collect();
+    if hosts.is_empty() {
+        hosts.push(None);
+    }
+    for profile in &profiles {
+        for host in &hosts {
+            for problem in check::check_duplicate_items((&file, &content), profile.as_deref(), *host) {
+                if !problems.iter().any(|known| known.to_string() == problem.to_string()) {
+                    problems.push(problem);
+                }
+            }
+        }
+    }
+
+    // Dependency cycles and overlays naming unknown tasks surface when
+    // loading the config with each profile.
+    if !problems.iter().any(Problem::is_error) {
+        for profile in profiles {
+            let Err(err) = load_config(&LoadOptions { profile: profile.clone(), ..options.clone() }) else { continue };
+            let message = match &profile {
+                Some(profile) => format!("with profile `{}`: {:#}", profile, err),
+                None => format!("{:#}", err),
+            };
+            if !problems.iter().any(|problem| problem.message == message) {
+                problems.push(Problem { severity: Severity::Error, message, location: None });
+            }
+        }
+    }
+
+    let errors = problems.iter().filter(|problem| problem.is_error()).count();
+    let warnings = problems.len() - errors;
+    for problem in &problems {
+        match args.format {
+            Format::Text => println!("{}\n", problem),
+            Format::Json => println!("{}", json!(problem)),
+        }
+    }
+    if args.format == Format::Text {
+        match (errors, warnings) {
+            (0, 0) => println!("{}: no problems found", source),
+            (errors, warnings) => println!("{}: {} error(s), {} warning(s)", source, errors, warnings),
+        }
+    }
+    if errors > 0 {
+        Ok(ExitCode::from(EXIT_USAGE))
+    } else {
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+fn schema(args: SchemaArgs) -> CliResult {
+    // Draft 7 is what taplo understands best.
+    let generator = SchemaSettings::draft07().into_generator();
+    let schema = if args.overlay {
+        generator.into_root_schema_for::<Overlay>()
+    } else {
+        generator.into_root_schema_for::<Config>()
+    };
+    let json = serde_json::to_string_pretty(&schema).map_err(|err| usage(err.into()))?;
+    println!("{}", json);
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Prefixed to the ids of the steps `unstow` runs, which reuse stow tasks.
+const UNSTOW_PREFIX: &str = "unstow:";
+
+/// Runs the selected `stow` tasks as `unstow` tasks.
+fn unstow(args: UnstowArgs, options: &LoadOptions) -> CliResult {
+    let config = load_config(options).map_err(usage)?;
+    let on_failure = config.on_failure;
+    let (source, profile) = (config.source.to_string(), config.profile.clone());
+    let stow_tasks: Vec<Task> = config
+        .tasks
+        .into_iter()
+        .filter(|t| matches!(t.kind, TaskKind::Stow(_)))
+        .collect();
+    if let Some(unknown) = args.tasks.iter().find(|id| !stow_tasks.iter().any(|t| &t.id == *id)) {
+        let known: Vec<&str> = stow_tasks.iter().map(|t| t.id.as_str()).collect();
+        return Err(usage(eyre!("unknown stow task `{}` (stow tasks: {})", unknown, known.join(", "))));
+    }
+
+    let tasks: Vec<Task> = stow_tasks
+        .into_iter()
+        .filter(|t| args.tasks.is_empty() || args.tasks.contains(&t.id))
+        .map(|mut task| {
+            if let TaskKind::Stow(stow) = task.kind {
+                task.kind = TaskKind::Unstow(stow);
+            }
+            // Keeps a resumed run from picking the stow task back up.
+            task.id = format!("{}{}", UNSTOW_PREFIX, task.id);
+            task.name = format!("Undo {}", task.name);
+            task.depends_on.clear();
+            task
+        })
+        .collect();
+
+    let reporter = reporter(args.output.format, &tasks, args.dry_run, false);
+    let options = RunOptions { dry_run: args.dry_run, jobs: 1, on_failure };
+    let statuses = if args.dry_run {
+        engine::run_tasks(&tasks, None, &options, &cancel_on_signal(), reporter)
+    } else {
+        let resolved = Resolved { source, profile, jobs: 1, tasks: &tasks };
+        run_logged(&tasks, None, &options, &resolved, reporter, args.output.format)
+    };
+    if statuses.iter().any(|status| matches!(status, StepStatus::Failed | StepStatus::Cancelled)) {
+        Ok(ExitCode::from(EXIT_TASK_FAILED))
+    } else {
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+fn restore(args: RestoreArgs) -> CliResult {
+    let Some(run_id) = args.run_id else {
+        for run in stow::backup_runs() {
+            println!("{}", run);
+        }
+        return Ok(ExitCode::SUCCESS);
+    };
+    stow::restore(&run_id, &|line| println!("{}", line)).map_err(usage)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_tasks(mut args: RunArgs, options: &LoadOptions) -> CliResult {
+    let config = load_config(options).map_err(usage)?;
+    if args.resume {
+        let run = runlog::resumable().ok_or_else(|| usage(eyre!("the last run has nothing left to resume")))?;
+        for step in run.unfinished() {
+            if config.tasks.iter().any(|t| t.id == step.id) {
+                args.select.tasks.push(step.id.clone());
+            } else if step.id.starts_with(UNSTOW_PREFIX) {
+                eprintln!("warning: `{}` was left by `dot-setup unstow`, run that again instead", step.id);
+            } else {
+                eprintln!("warning: `{}` is no longer in the config and is skipped", step.id);
+            }
+        }
+        if args.select.tasks.is_empty() {
+            return Err(usage(eyre!("none of the steps left in run {} are in the config", run.id)));
+        }
+    }
+    let (jobs, on_failure) = (args.jobs.unwrap_or(config.jobs), config.on_failure);
+    let (source, profile) = (config.source.to_string(), config.profile.clone());
+    let tasks = select_tasks(config, &args.select).map_err(usage)?;
+    if tasks.is_empty() {
+        return Err(usage(eyre!("no tasks selected")));
+    }
+
+    if !args.yes {
+        confirm(&tasks).map_err(usage)?;
+    }
+
+    let sudo = if tasks.iter().any(|t| t.kind.needs_sudo()) && !privilege::is_root() {
+        Some(Arc::new(authenticate(args.password_stdin).map_err(|err| (EXIT_AUTH, err))?))
+    } else {
+        None
+    };
+
+    let format = args.select.output.format;
+    let resolved = Resolved { source, profile, jobs, tasks: &tasks };
+    let reporter = reporter(format, &tasks, false, jobs > 1);
+    let statuses = run_logged(&tasks, sudo, &RunOptions { dry_run: false, jobs, on_failure }, &resolved, reporter, format);
+
+    if statuses.iter().any(|status| matches!(status, StepStatus::Failed | StepStatus::Cancelled)) {
+        Ok(ExitCode::from(EXIT_TASK_FAILED))
+    } else {
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+/// The running flag handed to the engine, cleared by SIGINT and SIGTERM.
+static RUNNING: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+/// Returns the running flag after installing the SIGINT and SIGTERM
+/// handlers that clear it. Commands run in their own process groups, so
+/// Ctrl-C doesn't reach them; clearing the flag makes the executor kill
+/// them. A second signal is handled the default way, ending dot-setup at
+/// once.
+fn cancel_on_signal() -> Arc<AtomicBool> {
+    extern "C" fn cancel(signal: libc::c_int) {
+        if let Some(running) = RUNNING.get() {
+            running.store(false, Ordering::SeqCst);
+        }
+        unsafe { libc::signal(signal, libc::SIG_DFL) };
+    }
+
+    let running = RUNNING.get_or_init(|| Arc::new(AtomicBool::new(true)));
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        unsafe { libc::signal(signal, cancel as extern "C" fn(libc::c_int) as libc::sighandler_t) };
+    }
+    Arc::clone(running)
+}
+
+/// Runs `tasks` with a run log in the state directory, pointing at it
+/// afterwards. A log that can't be created is only a warning.
+fn run_logged(
+    tasks: &[Task],
+    sudo: Option<Arc<Sudo>>,
+    options: &RunOptions,
+    resolved: &Resolved,
+    reporter: Arc<dyn Reporter>,
+    format: Format,
+) -> Vec<StepStatus> {
+    let log = RunLog::create(resolved, Arc::clone(&reporter))
+        .inspect_err(|err| eprintln!("warning: could not create a run log: {:#}", err))
+        .ok();
+    let reporter = log.clone().map_or(reporter, |log| log as Arc<dyn Reporter>);
+    let statuses = engine::run_tasks(tasks, sudo, options, &cancel_on_signal(), reporter);
+
+    if let Some(log) = log {
+        log.finish(&statuses);
+        match format {
+            Format::Text => println!("Log: {} (`dot-setup log {}`)", log.dir().display(), log.id()),
+            Format::Json => println!("{}", json!({ "event": "logged", "run": log.id(), "dir": log.dir() })),
+        }
+    }
+    statuses
+}
+
+fn history(args: OutputArgs) -> CliResult {
+    for run in runlog::runs().into_iter().rev() {
+        let failed = run.steps.iter().filter(|step| step.status == StepStatus::Failed).count();
+        match args.format {
+            Format::Text => {
+                let duration = run.duration().map(|secs| Elapsed(secs).to_string()).unwrap_or_default();
+                let steps = match failed {
+                    0 => format!("{} steps", run.steps.len()),
+                    n => format!("{} steps, {} failed", run.steps.len(), n),
+                };
+                let profile = run.profile.as_deref().unwrap_or("-");
+                println!("{:<20} {:<10} {:>7} {:<14} {}", run.id, run.outcome_name(), duration, profile, steps);
+            }
+            Format::Json => println!("{}", json!(run)),
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn log(args: LogArgs) -> CliResult {
+    let run = runlog::find(&args.run).map_err(usage)?;
+    let log = runlog::read_log(&run, args.step.as_deref()).map_err(usage)?;
+    print!("{}", log);
+    if args.step.is_none() {
+        println!();
+        for step in &run.steps {
+            let duration = step.duration.map(|secs| format!("{:.1}s", secs)).unwrap_or_default();
+            let exit = match (step.exit_code, step.signal) {
+                (Some(code), _) => format!("exit {}", code),
+                (None, Some(signal)) => format!("signal {}", signal),
+                (None, None) => String::new(),
+            };
+            println!("{:<14} {:<13} {:>8} {}", step.id, step.status.name(), duration, exit);
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// The tasks named by `--tasks` in config order, or the ones enabled in
+/// config.toml when none are named, with `--on-conflict` applied.
+fn select_tasks(config: Config, args: &SelectArgs) -> Result<Vec<Task>> {
+    let ids = &args.tasks;
+    if let Some(unknown) = ids.iter().find(|id| !config.tasks.iter().any(|t| &t.id == *id)) {
+        let known: Vec<&str> = config.tasks.iter().map(|t| t.id.as_str()).collect();
+        return Err(eyre!("unknown task `{}` (known tasks: {})", unknown, known.join(", ")));
+    }
+
+    let mut tasks: Vec<Task> = config
+        .tasks
+        .into_iter()
+        .filter(|t| if ids.is_empty() { t.enabled } else { ids.contains(&t.id) })
+        .collect();
+    if let Some(resolution) = args.on_conflict {
+        for task in &mut tasks {
+            if let TaskKind::Stow(stow) = &mut task.kind {
+                stow.on_conflict = resolution;
+            }
+        }
+    }
+    Ok(tasks)
+}
+
+fn confirm(tasks: &[Task]) -> Result<()> {
+    if !io::stdin().is_terminal() {
+        return Err(eyre!("stdin is not a terminal; pass --yes to run without confirmation"));
+    }
+
+    println!("The following tasks will run:");
+    for task in tasks {
+        println!("  {} ({})", task.name, task.id);
+    }
+    print!("Continue? [y/N] ");
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    if !matches!(answer.trim(), "y" | "Y" | "yes") {
+        return Err(eyre!("aborted"));
+    }
+    Ok(())
+}
+
+fn authenticate(password_stdin: bool) -> Result<Sudo> {
+    if password_stdin {
+        let mut password = Zeroizing::new(String::new());
+        io::stdin().lock().read_line(&mut password)?;
+        let len = password.trim_end_matches(['\n', '\r']).len();
+        password.truncate(len);
+        return Sudo::validate(password);
+    }
+    if let Some(sudo) = Sudo::cached() {
+        return Ok(sudo);
+    }
+    if io::stdin().is_terminal() {
+        return Sudo::prompt();
+    }
+    Err(eyre!("sudo needs a password; pass --password-stdin or run `sudo -v` first"))
+}
+
+fn reporter(format: Format, tasks: &[Task], plan: bool, parallel: bool) -> Arc<dyn Reporter> {
+    let ids: Vec<String> = tasks.iter().map(|t| t.id.clone()).collect();
+    match format {
+        Format::Text => Arc::new(TextReporter { plan, ids: parallel.then_some(ids) }),
+        Format::Json => Arc::new(JsonReporter { ids }),
+    }
+}
+
+/// Prints progress the way the Output pane shows it.
+struct TextReporter {
+    /// Plans have no outcome worth printing after each task.
+    plan: bool,
+    /// Set when tasks run in parallel, so interleaved output lines can be
+    /// prefixed with the id of the task that printed them.
+    ids: Option<Vec<String>>,
+}
+
+impl Reporter for TextReporter {
+    fn task_started(&self, _index: usize, task: &Task) {
+        println!("=== {} ===", task.name);
+    }
+
+    fn output(&self, index: usize, line: String) {
+        match &self.ids {
+            Some(ids) => println!("[{}] {}", ids[index], line),
+            None => println!("{}", line),
+        }
+    }
+
+    fn task_finished(&self, _index: usize, task: &Task, status: StepStatus, error: &[String]) {
+        if self.plan {
+            return;
+        }
+        for line in engine::status_lines(task, status, error) {
+            println!("{}", line);
+        }
+    }
+}
+
+/// Prints one JSON object per event for scripts and log collectors.
+struct JsonReporter {
+    ids: Vec<String>,
+}
+
+impl Reporter for JsonReporter {
+    fn task_started(&self, _index: usize, task: &Task) {
+        println!("{}", json!({ "event": "task_started", "task": task.id, "name": task.name }));
+    }
+
+    fn output(&self, index: usize, line: String) {
+        println!("{}", json!({ "event": "output", "task": self.ids[index], "line": line }));
+    }
+
+    fn task_finished(&self, _index: usize, task: &Task, status: StepStatus, error: &[String]) {
+        println!(
+            "{}",
+            json!({ "event": "task_finished", "task": task.id, "status": status, "error": error })
+        );
+    }
+}
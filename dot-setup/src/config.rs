@@ -0,0 +1,888 @@
+use crate::check;
+use crate::distro::{self, Distro};
+use crate::graph;
+use crate::state;
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::Result;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// dot-setup's config.toml.
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct Config {
+    /// How many independent tasks may run at the same time.
+    #[serde(default = "default_jobs")]
+    pub jobs: usize,
+    /// What happens when a task fails, unless the task sets its own
+    /// `on_failure`.
+    #[serde(default)]
+    pub on_failure: OnFailure,
+    /// Variables available to `*.tmpl` dotfiles.
+    #[serde(default)]
+    #[schemars(with = "serde_json::Map<String, serde_json::Value>")]
+    pub vars: toml::Table,
+    pub tasks: Vec<Task>,
+    /// Profile applied when neither `--profile` nor a host overlay picks one.
+    #[serde(default)]
+    pub default_profile: Option<String>,
+    /// Named overlays such as `workstation`, `server` or `vm`.
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Overlay>,
+    /// The profile that was applied by [`load_config`].
+    #[serde(skip)]
+    pub profile: Option<String>,
+    /// Where the config was read from.
+    #[serde(skip)]
+    pub source: ConfigSource,
+}
+
+fn default_jobs() -> usize {
+    1
+}
+
+/// One entry of the ordered `[[tasks]]` list in config.toml.
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct Task {
+    /// Unique name used by `depends_on`, `--tasks` and overlays.
+    pub id: String,
+    /// Shown on the selection screen and in progress output.
+    pub name: String,
+    /// Whether the task starts checked on the selection screen.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Shell command that exits 0 when the task has nothing left to do.
+    /// Overrides the check derived from the task's kind.
+    #[serde(default)]
+    pub check: Option<String>,
+    /// Ids of the tasks that have to finish before this one starts.
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// `/etc/os-release` ids (or `ID_LIKE` entries) this task applies to;
+    /// empty means every distribution.
+    #[serde(default)]
+    pub distros: Vec<String>,
+    /// Seconds a single command of the task may run before it is stopped
+    /// and counted as failed.
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    /// How many times a failing command is run again before the task
+    /// fails, at most 10.
+    #[serde(default)]
+    pub retries: u32,
+    /// Seconds to wait before the first retry, doubled for each one after
+    /// up to 10 minutes.
+    #[serde(default = "default_retry_backoff")]
+    pub retry_backoff: u64,
+    /// What happens to the rest of the run when this task fails; defaults
+    /// to the top-level `on_failure`.
+    #[serde(default)]
+    pub on_failure: Option<OnFailure>,
+    #[serde(flatten)]
+    pub kind: TaskKind,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_retry_backoff() -> u64 {
+    5
+}
+
+/// The most `retries` a task may ask for.
+pub const MAX_RETRIES: u64 = 10;
+
+/// The longest wait between two attempts, in seconds.
+pub const MAX_RETRY_BACKOFF: u64 = 600;
+
+impl Task {
+    pub fn applies_to(&self, distro: &Distro) -> bool {
+        self.distros.is_empty() || self.distros.iter().any(|name| distro.matches(name))
+    }
+}
+
+/// What a task does, selected by its `kind` key.
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum TaskKind {
+    /// Adds dnf repositories.
+    DnfRepos(Repositories),
+    /// Installs packages with dnf.
+    Dnf(PackageGroup),
+    /// Adds apt sources and their signing keys.
+    AptRepos(AptRepositories),
+    /// Installs packages with apt-get.
+    Apt(AptGroup),
+    /// Installs Flatpak apps from one remote.
+    Flatpak(FlatpakGroup),
+    /// Installs Homebrew, then packages with it.
+    Brew(HomebrewGroup),
+    /// Installs crates with `cargo install`.
+    Cargo(CargoGroup),
+    /// Runs a shell command.
+    Shell(ShellCommand),
+    /// Links the dotfiles of a package directory into `target`.
+    Stow(StowTask),
+    /// Removes the links a `stow` task with the same `dir` and `target`
+    /// created.
+    Unstow(StowTask),
+}
+
+/// Changes layered on top of config.toml, either a `[profiles.<name>]`
+/// table or a `hosts/<hostname>.toml` file next to config.toml.
+///
+/// An overlay is applied in a fixed order: `vars` and `jobs` first, then
+/// `remove_tasks`, then `tasks` (replacing a task with the same id or
+/// appending), then `modify`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Overlay {
+    /// Profile to use on this host. Only read from host overlays.
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Replaces the top-level `jobs`.
+    #[serde(default)]
+    pub jobs: Option<usize>,
+    /// Replaces the top-level `on_failure`.
+    #[serde(default)]
+    pub on_failure: Option<OnFailure>,
+    /// Added to the top-level `vars`, replacing variables with the same name.
+    #[serde(default)]
+    #[schemars(with = "serde_json::Map<String, serde_json::Value>")]
+    pub vars: toml::Table,
+    /// Ids of tasks to drop.
+    #[serde(default)]
+    pub remove_tasks: Vec<String>,
+    #[serde(default)]
+    pub tasks: Vec<Task>,
+    /// Item changes by task id.
+    #[serde(default)]
+    pub modify: BTreeMap<String, TaskChanges>,
+}
+
+/// Packages, apps or repositories to add to or remove from one task.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct TaskChanges {
+    #[serde(default)]
+    pub add: Vec<String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+    /// Overrides whether the task starts checked.
+    #[serde(default)]
+    pub enabled: Option<bool>,
+}
+
+impl Config {
+    /// Layers `overlay` on top of this config; `origin` names it in errors.
+    pub fn apply(&mut self, overlay: &Overlay, origin: &str) -> Result<()> {
+        if let Some(jobs) = overlay.jobs {
+            self.jobs = jobs;
+        }
+        if let Some(on_failure) = overlay.on_failure {
+            self.on_failure = on_failure;
+        }
+        self.vars.extend(overlay.vars.clone());
+
+        for id in &overlay.remove_tasks {
+            if !self.tasks.iter().any(|task| &task.id == id) {
+                return Err(eyre!("{}: remove_tasks names unknown task `{}`", origin, id));
+            }
+        }
+        self.tasks.retain(|task| !overlay.remove_tasks.contains(&task.id));
+
+        for task in &overlay.tasks {
+            match self.tasks.iter_mut().find(|existing| existing.id == task.id) {
+                Some(existing) => *existing = task.clone(),
+                None => self.tasks.push(task.clone()),
+            }
+        }
+
+        for (id, changes) in &overlay.modify {
+            let task = self
+                .tasks
+                .iter_mut()
+                .find(|task| &task.id == id)
+                .ok_or_else(|| eyre!("{}: modify names unknown task `{}`", origin, id))?;
+            task.kind.retain_items(|item| !changes.remove.iter().any(|name| name == item));
+            for item in &changes.add {
+                if !task.kind.items().contains(&item.as_str()) {
+                    task.kind.push_item(item).wrap_err_with(|| format!("{}: task `{}`", origin, id))?;
+                }
+            }
+            if let Some(enabled) = changes.enabled {
+                task.enabled = enabled;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Every `kind` a task can have, as returned by [`TaskKind::name`].
+pub const KINDS: &[&str] =
+    &["dnf-repos", "dnf", "apt-repos", "apt", "flatpak", "brew", "cargo", "shell", "stow", "unstow"];
+
+impl TaskKind {
+    /// The `kind` key this variant is selected by.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TaskKind::DnfRepos(_) => "dnf-repos",
+            TaskKind::Dnf(_) => "dnf",
+            TaskKind::AptRepos(_) => "apt-repos",
+            TaskKind::Apt(_) => "apt",
+            TaskKind::Flatpak(_) => "flatpak",
+            TaskKind::Brew(_) => "brew",
+            TaskKind::Cargo(_) => "cargo",
+            TaskKind::Shell(_) => "shell",
+            TaskKind::Stow(_) => "stow",
+            TaskKind::Unstow(_) => "unstow",
+        }
+    }
+
+    /// The individually selectable entries of the task: packages, apps or
+    /// repositories, depending on the kind.
+    pub fn items(&self) -> Vec<&str> {
+        let names: Box<dyn Iterator<Item = &String>> = match self {
+            TaskKind::Dnf(group) => Box::new(group.packages.iter()),
+            TaskKind::AptRepos(repos) => Box::new(repos.repositories.iter().map(|repo| &repo.name)),
+            TaskKind::Apt(group) => Box::new(group.packages.iter()),
+            TaskKind::Flatpak(group) => Box::new(group.apps.iter()),
+            TaskKind::Brew(group) => Box::new(group.packages.iter()),
+            TaskKind::Cargo(group) => Box::new(group.packages.iter()),
+            TaskKind::DnfRepos(_) | TaskKind::Shell(_) | TaskKind::Stow(_) | TaskKind::Unstow(_) => {
+                Box::new(std::iter::empty())
+            }
+        };
+        names.map(String::as_str).collect()
+    }
+
+    /// Keeps only the [`items`](Self::items) for which `keep` returns true.
+    pub fn retain_items(&mut self, mut keep: impl FnMut(&str) -> bool) {
+        match self {
+            TaskKind::Dnf(group) => group.packages.retain(|pkg| keep(pkg)),
+            TaskKind::AptRepos(repos) => repos.repositories.retain(|repo| keep(&repo.name)),
+            TaskKind::Apt(group) => group.packages.retain(|pkg| keep(pkg)),
+            TaskKind::Flatpak(group) => group.apps.retain(|app| keep(app)),
+            TaskKind::Brew(group) => group.packages.retain(|pkg| keep(pkg)),
+            TaskKind::Cargo(group) => group.packages.retain(|pkg| keep(pkg)),
+            TaskKind::DnfRepos(_) | TaskKind::Shell(_) | TaskKind::Stow(_) | TaskKind::Unstow(_) => {}
+        }
+    }
+
+    /// Appends an entry to the task's [`items`](Self::items).
+    pub fn push_item(&mut self, item: &str) -> Result<()> {
+        let item = item.to_string();
+        match self {
+            TaskKind::Dnf(group) => group.packages.push(item),
+            TaskKind::Apt(group) => group.packages.push(item),
+            TaskKind::Flatpak(group) => group.apps.push(item),
+            TaskKind::Brew(group) => group.packages.push(item),
+            TaskKind::Cargo(group) => group.packages.push(item),
+            TaskKind::AptRepos(_) => {
+                return Err(eyre!("apt repositories can't be added by name; replace the task instead"))
+            }
+            TaskKind::DnfRepos(_) | TaskKind::Shell(_) | TaskKind::Stow(_) | TaskKind::Unstow(_) => {
+                return Err(eyre!("{} tasks have no items to add to", self.name()))
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the task runs anything through sudo, and so needs the
+    /// password prompt before it starts.
+    pub fn needs_sudo(&self) -> bool {
+        match self {
+            TaskKind::DnfRepos(_) | TaskKind::Dnf(_) | TaskKind::AptRepos(_) | TaskKind::Apt(_) => true,
+            // The Homebrew installer calls `sudo -n` itself to create its prefix.
+            TaskKind::Brew(_) => true,
+            TaskKind::Shell(shell) => shell.sudo,
+            TaskKind::Flatpak(_) | TaskKind::Cargo(_) | TaskKind::Stow(_) | TaskKind::Unstow(_) => false,
+        }
+    }
+
+    /// Whether the task runs dnf or apt transactions, which can leave the
+    /// package database locked or half-updated when killed.
+    pub fn is_package_transaction(&self) -> bool {
+        match self {
+            TaskKind::DnfRepos(_) | TaskKind::Dnf(_) | TaskKind::AptRepos(_) | TaskKind::Apt(_) => true,
+            TaskKind::Brew(_)
+            | TaskKind::Shell(_)
+            | TaskKind::Flatpak(_)
+            | TaskKind::Cargo(_)
+            | TaskKind::Stow(_)
+            | TaskKind::Unstow(_) => false,
+        }
+    }
+}
+
+/// dnf repositories added by a `dnf-repos` task.
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct Repositories {
+    /// Release RPMs (e.g. RPM Fusion) installed with `dnf install`.
+    #[serde(default)]
+    pub release_packages: Vec<String>,
+    /// `.repo` files added with `dnf config-manager addrepo`.
+    #[serde(default)]
+    pub repo_files: Vec<String>,
+    /// Repositories whose release package has to be fetched from the repo
+    /// itself (e.g. Terra).
+    #[serde(default)]
+    pub bootstrap: Vec<BootstrapRepo>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct BootstrapRepo {
+    /// Repository id used while installing `package`.
+    pub name: String,
+    pub baseurl: String,
+    /// The release package that sets up the repository for good.
+    pub package: String,
+}
+
+/// Packages installed by a `dnf` task.
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct PackageGroup {
+    /// Package names, or URLs of RPMs.
+    pub packages: Vec<String>,
+    /// Run `dnf upgrade -y` before installing.
+    #[serde(default)]
+    pub upgrade: bool,
+    /// systemd units to `enable --now` after installing.
+    #[serde(default)]
+    pub services: Vec<String>,
+}
+
+/// apt sources added by an `apt-repos` task.
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct AptRepositories {
+    pub repositories: Vec<AptRepository>,
+}
+
+/// A one-line-style apt source written to
+/// `/etc/apt/sources.list.d/<name>.list`. `{codename}` in `uri` and
+/// `suites` is replaced with the host's `VERSION_CODENAME`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct AptRepository {
+    pub name: String,
+    pub uri: String,
+    pub suites: Vec<String>,
+    pub components: Vec<String>,
+    #[serde(default)]
+    pub arch: Option<String>,
+    /// Signing key downloaded to `/etc/apt/keyrings/<name>.asc` and
+    /// referenced with `signed-by`. Without it apt's default keyring is used.
+    #[serde(default)]
+    pub key_url: Option<String>,
+}
+
+/// Packages installed by an `apt` task.
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct AptGroup {
+    pub packages: Vec<String>,
+    /// Run `apt-get update` before installing.
+    #[serde(default)]
+    pub update_cache: bool,
+    /// systemd units to `enable --now` after installing.
+    #[serde(default)]
+    pub services: Vec<String>,
+}
+
+/// Flatpak apps installed from one remote by a `flatpak` task.
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct FlatpakGroup {
+    /// Remote name, e.g. `flathub`; added if missing.
+    pub remote: String,
+    /// The `.flatpakrepo` URL the remote is added from.
+    pub remote_url: String,
+    /// Application ids, e.g. `org.telegram.desktop`.
+    pub apps: Vec<String>,
+}
+
+/// Homebrew and the formulae a `brew` task installs with it.
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct HomebrewGroup {
+    /// URL of the Homebrew install script, run when `brew` isn't installed.
+    pub install_script: String,
+    /// Command printing the environment `brew` needs, e.g. `brew shellenv`.
+    pub shell_init: String,
+    /// Formulae, optionally `tap/name`.
+    pub packages: Vec<String>,
+}
+
+/// Crates installed by a `cargo` task.
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct CargoGroup {
+    pub packages: Vec<String>,
+}
+
+/// The command a `shell` task runs with `sh -c`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct ShellCommand {
+    pub command: String,
+    /// Run the command as root.
+    #[serde(default)]
+    pub sudo: bool,
+}
+
+/// Where a `stow` or `unstow` task links dotfiles from and to.
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct StowTask {
+    /// The package directory whose contents get linked; `~` is expanded.
+    /// Relative paths are resolved against the directory config.toml is
+    /// in, which has to be a checkout of the repository: they are refused
+    /// in the built-in default and in `$XDG_CONFIG_HOME/dot-setup`.
+    #[serde(default = "default_stow_dir")]
+    pub dir: PathBuf,
+    /// Where the links are created.
+    #[serde(default = "default_stow_target")]
+    pub target: String,
+    /// Directories created before stowing so their contents are linked one
+    /// by one instead of folding the whole directory into one link.
+    #[serde(default)]
+    pub ensure_dirs: Vec<String>,
+    /// What to do with an existing file where a link should go.
+    #[serde(default)]
+    pub on_conflict: Resolution,
+    /// Template variables for this task, on top of the top-level `vars`.
+    #[serde(default)]
+    #[schemars(with = "serde_json::Map<String, serde_json::Value>")]
+    pub vars: toml::Table,
+    /// Choices made per conflicting target on the TUI's Conflicts screen;
+    /// targets not listed fall back to `on_conflict`.
+    #[serde(skip)]
+    pub resolutions: BTreeMap<PathBuf, Resolution>,
+}
+
+fn default_stow_dir() -> PathBuf {
+    PathBuf::from("..")
+}
+
+fn default_stow_target() -> String {
+    "$HOME".to_string()
+}
+
+/// What the rest of a run does when a task fails.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnFailure {
+    /// Start no further tasks; the ones already running finish.
+    Stop,
+    /// Run every other task, including the ones that depend on it.
+    Continue,
+    /// Skip the tasks that depend on it, directly or not, and run the rest.
+    #[default]
+    SkipDependents,
+}
+
+/// How a stow task deals with a file or directory that is in the way of a link.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Default, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Resolution {
+    /// Leave it alone and don't create the link
+    Skip,
+    /// Move it to a backup directory, then link
+    #[default]
+    Backup,
+    /// Move it into the repository in place of the repo's copy, then link
+    Adopt,
+}
+
+impl Resolution {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Resolution::Skip => "skip",
+            Resolution::Backup => "backup",
+            Resolution::Adopt => "adopt",
+        }
+    }
+}
+
+/// The config.toml compiled into the binary, used when none is found on disk.
+const DEFAULT_CONFIG: &str = include_str!("../config.toml");
+
+/// What [`load_config`] reads: an explicit config file and profile, or
+/// `None` to search for them.
+#[derive(Clone, Default)]
+pub struct LoadOptions {
+    pub config: Option<PathBuf>,
+    pub profile: Option<String>,
+}
+
+/// The config.toml that was loaded.
+#[derive(Clone)]
+pub enum ConfigSource {
+    /// A file, and where the search found it, e.g. `--config`.
+    File { path: PathBuf, origin: &'static str },
+    /// The built-in default, after none of `tried` existed.
+    Embedded { tried: Vec<(&'static str, PathBuf)> },
+}
+
+impl Default for ConfigSource {
+    fn default() -> Self {
+        ConfigSource::Embedded { tried: Vec::new() }
+    }
+}
+
+impl ConfigSource {
+    pub fn read(&self) -> Result<String> {
+        match self {
+            ConfigSource::File { path, .. } => {
+                fs::read_to_string(path).wrap_err_with(|| format!("could not read {}", path.display()))
+            }
+            ConfigSource::Embedded { .. } => Ok(DEFAULT_CONFIG.to_string()),
+        }
+    }
+
+    /// The directory holding the config file, which `hosts/` overlays sit
+    /// in. The built-in default has none.
+    pub fn base_dir(&self) -> Option<PathBuf> {
+        match self {
+            ConfigSource::File { path, .. } => Some(path.parent().unwrap_or(Path::new(".")).to_path_buf()),
+            ConfigSource::Embedded { .. } => None,
+        }
+    }
+
+    /// The directory a relative stow `dir` resolves against: the one
+    /// holding the config file, unless that is the built-in default or a
+    /// copy in `$XDG_CONFIG_HOME`, neither of which sits in a checkout of
+    /// the repository. Resolving against the current directory or
+    /// `~/.config` would link the wrong tree into the home directory.
+    pub fn repo_dir(&self) -> Option<PathBuf> {
+        match self {
+            ConfigSource::File { origin: XDG_ORIGIN, .. } => None,
+            _ => self.base_dir(),
+        }
+    }
+
+    /// The file and where the search found it, or that the built-in default
+    /// is in use, for the TUI header and `doctor`.
+    pub fn describe(&self) -> String {
+        match self {
+            ConfigSource::File { path, origin } => format!("{} ({})", path.display(), origin),
+            ConfigSource::Embedded { .. } => "built-in default (no config.toml found)".to_string(),
+        }
+    }
+
+    /// Every `hosts/*.toml` overlay next to the config file, sorted.
+    pub fn host_overlays(&self) -> Vec<PathBuf> {
+        let Some(base) = self.base_dir() else { return Vec::new() };
+        let mut paths: Vec<PathBuf> = fs::read_dir(base.join("hosts"))
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+            .collect();
+        paths.sort();
+        paths
+    }
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::File { path, .. } => write!(f, "{}", path.display()),
+            ConfigSource::Embedded { .. } => write!(f, "built-in default"),
+        }
+    }
+}
+
+/// An explicitly requested config file that doesn't exist, along with every
+/// path looked at.
+#[derive(Debug)]
+pub struct NotFound {
+    pub tried: Vec<(&'static str, PathBuf)>,
+}
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "config file not found; tried:")?;
+        for (origin, path) in &self.tried {
+            write!(f, "\n  {} ({})", path.display(), origin)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+const XDG_ORIGIN: &str = "$XDG_CONFIG_HOME";
+
+/// Finds config.toml, in order: `--config`, `$DOT_SETUP_CONFIG`,
+/// `$XDG_CONFIG_HOME/dot-setup/config.toml`, the repository checkout the
+/// binary was built in or run from, and finally the built-in default.
+/// The first two have to exist when given.
+pub fn find_config(explicit: Option<&Path>) -> std::result::Result<ConfigSource, NotFound> {
+    let mut tried = Vec::new();
+    let env_path = std::env::var_os("DOT_SETUP_CONFIG").filter(|path| !path.is_empty()).map(PathBuf::from);
+    for (origin, path) in [("--config", explicit.map(Path::to_path_buf)), ("$DOT_SETUP_CONFIG", env_path)] {
+        if let Some(path) = path {
+            if path.is_file() {
+                return Ok(ConfigSource::File { path, origin });
+            }
+            tried.push((origin, path));
+            return Err(NotFound { tried });
+        }
+    }
+
+    let mut candidates = vec![(XDG_ORIGIN, state::config_home().join("dot-setup/config.toml"))];
+    // A release build lives in dot-setup/target/release next to config.toml.
+    if let Ok(exe) = std::env::current_exe() {
+        for dir in exe.ancestors().skip(1).take(3) {
+            candidates.push(("next to the executable", dir.join("config.toml")));
+        }
+    }
+    candidates.push(("current directory", PathBuf::from("config.toml")));
+    candidates.push(("current directory", PathBuf::from("dot-setup/config.toml")));
+
+    for (origin, path) in candidates {
+        if path.is_file() {
+            return Ok(ConfigSource::File { path, origin });
+        }
+        tried.push((origin, path));
+    }
+    Ok(ConfigSource::Embedded { tried })
+}
+
+/// Loads config.toml and applies the active profile, then the overlay for
+/// this hostname, keeping only the tasks that apply to this host's
+/// distribution.
+///
+/// The profile is `options.profile` when given, else the host overlay's
+/// `profile`, else `default_profile`. The built-in default has no host
+/// overlays. A relative stow `dir` resolves against
+/// [`ConfigSource::repo_dir`]; where there is none it stays relative, and
+/// stowing refuses it.
+pub fn load_config(options: &LoadOptions) -> Result<Config> {
+    let source = find_config(options.config.as_deref())?;
+    let content = source.read()?;
+    check::ensure_valid(check::check_config(&source.to_string(), &content))?;
+    let mut config: Config = toml::from_str(&content).wrap_err_with(|| format!("invalid config {}", source))?;
+    let host = match source.base_dir() {
+        Some(base) => load_host_overlay(&base)?,
+        None => None,
+    };
+    let profile = options
+        .profile
+        .clone()
+        .or_else(|| host.as_ref().and_then(|(_, overlay)| overlay.profile.clone()))
+        .or_else(|| config.default_profile.clone());
+    if let Some(name) = &profile {
+        let overlay = config.profiles.get(name).cloned().ok_or_else(|| {
+            let known: Vec<&str> = config.profiles.keys().map(String::as_str).collect();
+            eyre!("unknown profile `{}` (known: {})", name, known.join(", "))
+        })?;
+        config.apply(&overlay, &format!("profile `{}`", name))?;
+    }
+    if let Some((path, overlay)) = &host {
+        config.apply(overlay, &path.display().to_string())?;
+    }
+    config.profile = profile;
+    graph::validate(&config.tasks)?;
+
+    let repo = source.repo_dir();
+    for task in &mut config.tasks {
+        if let TaskKind::Stow(stow) | TaskKind::Unstow(stow) = &mut task.kind {
+            let dir = state::expand_home(&stow.dir.to_string_lossy());
+            stow.dir = match &repo {
+                Some(repo) if dir.is_relative() => {
+                    let dir = repo.join(dir);
+                    dir.canonicalize().unwrap_or(dir)
+                }
+                _ => dir,
+            };
+            let mut vars = config.vars.clone();
+            vars.extend(std::mem::take(&mut stow.vars));
+            stow.vars = vars;
+        }
+    }
+
+    config.source = source;
+
+    let distro = distro::detect();
+    config.tasks.retain(|task| task.applies_to(&distro));
+    Ok(config)
+}
+
+/// Reads `hosts/<hostname>.toml` next to config.toml, if there is one.
+fn load_host_overlay(base: &Path) -> Result<Option<(PathBuf, Overlay)>> {
+    let hostname = distro::hostname();
+    if hostname.is_empty() {
+        return Ok(None);
+    }
+    let path = base.join("hosts").join(format!("{}.toml", hostname));
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = fs::read_to_string(&path).wrap_err_with(|| format!("could not read {}", path.display()))?;
+    check::ensure_valid(check::check_overlay(&path.display().to_string(), &content))?;
+    let overlay = toml::from_str(&content).wrap_err_with(|| format!("invalid host overlay {}", path.display()))?;
+    Ok(Some((path, overlay)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = r#"
+jobs = 1
+
+[[tasks]]
+id = "base"
+name = "Base"
+kind = "apt"
+packages = ["vim", "curl"]
+
+[[tasks]]
+id = "media"
+name = "Media"
+kind = "apt"
+packages = ["mpv"]
+
+[[tasks]]
+id = "dev"
+name = "Dev"
+kind = "apt"
+packages = ["git"]
+
+[[tasks]]
+id = "hello"
+name = "Hello"
+kind = "shell"
+command = "echo hello"
+"#;
+
+    fn base() -> Config {
+        toml::from_str(BASE).unwrap()
+    }
+
+    fn overlay(content: &str) -> Overlay {
+        toml::from_str(content).unwrap()
+    }
+
+    fn ids(config: &Config) -> Vec<&str> {
+        config.tasks.iter().map(|task| task.id.as_str()).collect()
+    }
+
+    fn items<'a>(config: &'a Config, id: &str) -> Vec<&'a str> {
+        config.tasks.iter().find(|task| task.id == id).unwrap().kind.items()
+    }
+
+    #[test]
+    fn overlay_order() {
+        let mut config = base();
+        let overlay = overlay(
+            r#"
+jobs = 4
+on_failure = "stop"
+vars = { font_size = 12 }
+remove_tasks = ["media"]
+
+# Replaces `dev` in place, then `modify` changes the replacement.
+[[tasks]]
+id = "dev"
+name = "Dev Tools"
+kind = "apt"
+packages = ["git", "gdb"]
+
+[[tasks]]
+id = "extra"
+name = "Extra"
+kind = "apt"
+packages = ["htop"]
+
+[modify.dev]
+add = ["strace", "gdb"]
+remove = ["git"]
+
+[modify.base]
+enabled = false
+"#,
+        );
+        config.apply(&overlay, "profile `p`").unwrap();
+
+        assert_eq!(ids(&config), ["base", "dev", "hello", "extra"]);
+        assert_eq!(config.tasks[1].name, "Dev Tools");
+        assert_eq!(items(&config, "dev"), ["gdb", "strace"]);
+        assert_eq!(items(&config, "base"), ["vim", "curl"]);
+        assert!(!config.tasks[0].enabled);
+        assert_eq!(config.jobs, 4);
+        assert!(config.on_failure == OnFailure::Stop);
+        assert_eq!(config.vars.get("font_size"), Some(&toml::Value::Integer(12)));
+    }
+
+    #[test]
+    fn removed_tasks_can_be_added_back() {
+        let mut config = base();
+        let overlay = overlay(
+            r#"
+remove_tasks = ["media"]
+
+[[tasks]]
+id = "media"
+name = "Media"
+kind = "cargo"
+packages = ["ffsend"]
+"#,
+        );
+        config.apply(&overlay, "profile `p`").unwrap();
+        assert_eq!(ids(&config), ["base", "dev", "hello", "media"]);
+        assert_eq!(config.tasks[3].kind.name(), "cargo");
+    }
+
+    #[test]
+    fn unknown_ids() {
+        let err = base().apply(&overlay("remove_tasks = [\"nope\"]"), "profile `p`").err().unwrap();
+        assert_eq!(err.to_string(), "profile `p`: remove_tasks names unknown task `nope`");
+
+        let err = base().apply(&overlay("[modify.nope]\nadd = [\"x\"]"), "hosts/box.toml").err().unwrap();
+        assert_eq!(err.to_string(), "hosts/box.toml: modify names unknown task `nope`");
+
+        // Removed tasks are gone by the time `modify` runs.
+        let err = base().apply(&overlay("remove_tasks = [\"dev\"]\n[modify.dev]\nadd = [\"x\"]"), "p").err().unwrap();
+        assert_eq!(err.to_string(), "p: modify names unknown task `dev`");
+
+        let err = base().apply(&overlay("[modify.hello]\nadd = [\"x\"]"), "p").err().unwrap();
+        assert_eq!(format!("{:#}", err), "p: task `hello`: shell tasks have no items to add to");
+    }
+
+    /// A config.toml with three profiles that each set `jobs`, next to a
+    /// host overlay for this machine when `host` is given.
+    fn load(dir: &str, profile: Option<&str>, host: Option<&str>) -> Config {
+        let dir = std::env::temp_dir().join(format!("dot-setup-test-{}-{}", std::process::id(), dir));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("hosts")).unwrap();
+        let profiles = "default_profile = \"one\"\n[profiles.one]\njobs = 1\n[profiles.two]\njobs = 2\n[profiles.three]\njobs = 3\n";
+        fs::write(dir.join("config.toml"), format!("{}\n{}", profiles, BASE.replace("jobs = 1\n", ""))).unwrap();
+        if let Some(host) = host {
+            let hostname = distro::hostname();
+            assert!(!hostname.is_empty());
+            fs::write(dir.join("hosts").join(format!("{}.toml", hostname)), host).unwrap();
+        }
+        let options = LoadOptions { config: Some(dir.join("config.toml")), profile: profile.map(str::to_string) };
+        load_config(&options).unwrap()
+    }
+
+    #[test]
+    fn profile_precedence() {
+        let config = load("default-profile", None, None);
+        assert_eq!((config.profile.as_deref(), config.jobs), (Some("one"), 1));
+
+        let config = load("host-profile", None, Some("profile = \"two\""));
+        assert_eq!((config.profile.as_deref(), config.jobs), (Some("two"), 2));
+
+        let config = load("flag-profile", Some("three"), Some("profile = \"two\""));
+        assert_eq!((config.profile.as_deref(), config.jobs), (Some("three"), 3));
+    }
+
+    #[test]
+    fn host_overlay_applies_after_the_profile() {
+        let config = load("host-after-profile", Some("two"), Some("jobs = 5\n[modify.dev]\nadd = [\"gdb\"]\n"));
+        assert_eq!((config.profile.as_deref(), config.jobs), (Some("two"), 5));
+        assert_eq!(items(&config, "dev"), ["git", "gdb"]);
+    }
+}
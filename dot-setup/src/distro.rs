@@ -0,0 +1,116 @@
+use std::fs;
+
+/// The host distribution as described by `/etc/os-release`.
+#[derive(Clone, Default)]
+pub struct Distro {
+    /// e.g. `fedora`, `debian`
+    pub id: String,
+    /// Distributions this one derives from, e.g. `debian` on Ubuntu.
+    pub id_like: Vec<String>,
+    /// Release codename, e.g. `bookworm`. Fedora doesn't set one.
+    pub version_codename: Option<String>,
+    pub pretty_name: String,
+}
+
+impl Distro {
+    /// Whether a task restricted to `name` applies to this host.
+    pub fn matches(&self, name: &str) -> bool {
+        self.id == name || self.id_like.iter().any(|like| like == name)
+    }
+}
+
+/// Reads `/etc/os-release`, falling back to `/usr/lib/os-release`.
+pub fn detect() -> Distro {
+    ["/etc/os-release", "/usr/lib/os-release"]
+        .iter()
+        .find_map(|path| fs::read_to_string(path).ok())
+        .map(|content| parse(&content))
+        .unwrap_or_default()
+}
+
+/// This machine's hostname, or an empty string when it can't be read.
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length; gethostname
+    // truncates longer names.
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+fn parse(content: &str) -> Distro {
+    let mut distro = Distro::default();
+    for line in content.lines() {
+        let Some((key, value)) = line.split_once('=') else { continue };
+        let value = value.trim().trim_matches(|c| c == '"' || c == '\'').to_string();
+        match key.trim() {
+            "ID" => distro.id = value,
+            "ID_LIKE" => distro.id_like = value.split_whitespace().map(str::to_string).collect(),
+            "VERSION_CODENAME" if !value.is_empty() => distro.version_codename = Some(value),
+            "PRETTY_NAME" => distro.pretty_name = value,
+            _ => {}
+        }
+    }
+    distro
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Task;
+
+    const UBUNTU: &str = r#"PRETTY_NAME="Ubuntu 24.04.1 LTS"
+NAME="Ubuntu"
+VERSION_ID="24.04"
+ID=ubuntu
+ID_LIKE="ubuntu debian"
+VERSION_CODENAME=noble
+"#;
+
+    const FEDORA: &str = "# Fedora doesn't set a codename\nNAME=\"Fedora Linux\"\nID=fedora\nVERSION_ID=41\nPRETTY_NAME='Fedora Linux 41 (Workstation Edition)'\n\nVERSION_CODENAME=\"\"\n";
+
+    #[test]
+    fn parses_quoted_values_and_id_like() {
+        let distro = parse(UBUNTU);
+        assert_eq!(distro.id, "ubuntu");
+        assert_eq!(distro.id_like, ["ubuntu", "debian"]);
+        assert_eq!(distro.version_codename.as_deref(), Some("noble"));
+        assert_eq!(distro.pretty_name, "Ubuntu 24.04.1 LTS");
+    }
+
+    #[test]
+    fn empty_or_missing_codename() {
+        let distro = parse(FEDORA);
+        assert_eq!(distro.id, "fedora");
+        assert!(distro.id_like.is_empty());
+        assert_eq!(distro.version_codename, None);
+        assert_eq!(distro.pretty_name, "Fedora Linux 41 (Workstation Edition)");
+
+        assert_eq!(parse("ID=debian\n").version_codename, None);
+        assert_eq!(parse("").id, "");
+    }
+
+    fn task(distros: &[&str]) -> Task {
+        let distros: Vec<String> = distros.iter().map(|name| format!("{:?}", name)).collect();
+        toml::from_str(&format!(
+            "id = \"t\"\nname = \"T\"\nkind = \"shell\"\ncommand = \"true\"\ndistros = [{}]",
+            distros.join(", ")
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn distros_match_id_or_id_like() {
+        let (ubuntu, fedora) = (parse(UBUNTU), parse(FEDORA));
+        assert!(task(&[]).applies_to(&ubuntu));
+        assert!(task(&["debian"]).applies_to(&ubuntu));
+        assert!(task(&["ubuntu"]).applies_to(&ubuntu));
+        assert!(!task(&["debian"]).applies_to(&fedora));
+        assert!(task(&["debian", "fedora"]).applies_to(&fedora));
+        // Only whole names match.
+        assert!(!task(&["deb"]).applies_to(&ubuntu));
+        assert!(!task(&["fedora"]).applies_to(&Distro::default()));
+    }
+}
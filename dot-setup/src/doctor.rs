@@ -0,0 +1,101 @@
+use crate::config::{Task, TaskKind};
+use crate::privilege;
+use crate::stow::{self, LinkState};
+use crate::tasks;
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// Whether a command the selected tasks rely on is available.
+#[derive(Serialize)]
+pub struct ToolCheck {
+    pub tool: &'static str,
+    pub found: bool,
+    /// Ids of the tasks that run it.
+    pub needed_by: Vec<String>,
+    /// The task that installs it as a package, when it is missing.
+    pub installed_by: Option<String>,
+}
+
+impl ToolCheck {
+    /// A missing tool is fine when an earlier task installs it.
+    pub fn is_problem(&self) -> bool {
+        !self.found && self.installed_by.is_none()
+    }
+}
+
+/// The commands `task` shells out to. Stow tasks need nothing: linking is
+/// done by dot-setup itself.
+fn required_tools(task: &Task) -> Vec<&'static str> {
+    let mut tools = match &task.kind {
+        TaskKind::DnfRepos(_) | TaskKind::Dnf(_) => vec!["dnf"],
+        TaskKind::AptRepos(repos) if repos.repositories.iter().any(|repo| repo.key_url.is_some()) => {
+            vec!["apt-get", "curl"]
+        }
+        TaskKind::AptRepos(_) | TaskKind::Apt(_) => vec!["apt-get"],
+        TaskKind::Flatpak(_) => vec!["flatpak"],
+        TaskKind::Brew(_) => vec!["curl"],
+        TaskKind::Cargo(_) => vec!["cargo"],
+        TaskKind::Shell(_) | TaskKind::Stow(_) | TaskKind::Unstow(_) => Vec::new(),
+    };
+    if task.kind.needs_sudo() && !privilege::is_root() {
+        tools.push("sudo");
+    }
+    tools
+}
+
+/// Checks every tool the tasks need, in the order they are first needed.
+pub fn check_tools(tasks: &[Task]) -> Vec<ToolCheck> {
+    let mut checks: Vec<ToolCheck> = Vec::new();
+    for task in tasks {
+        for tool in required_tools(task) {
+            match checks.iter_mut().find(|check| check.tool == tool) {
+                Some(check) => check.needed_by.push(task.id.clone()),
+                None => checks.push(ToolCheck {
+                    tool,
+                    found: tasks::has_command(tool),
+                    needed_by: vec![task.id.clone()],
+                    installed_by: None,
+                }),
+            }
+        }
+    }
+
+    for check in checks.iter_mut().filter(|check| !check.found) {
+        check.installed_by = tasks
+            .iter()
+            .find(|task| task.kind.items().contains(&check.tool))
+            .map(|task| task.id.clone());
+    }
+    checks
+}
+
+/// The link state of every dotfile a stow task manages.
+#[derive(Serialize)]
+pub struct LinkCheck {
+    pub task: String,
+    pub target: PathBuf,
+    #[serde(flatten)]
+    pub state: LinkState,
+}
+
+impl LinkCheck {
+    pub fn is_problem(&self) -> bool {
+        !matches!(self.state, LinkState::Linked | LinkState::Rendered)
+    }
+}
+
+/// Link states for each stow task; a task whose package can't be read
+/// yields an error message instead.
+pub fn check_links(tasks: &[Task]) -> Vec<Result<LinkCheck, String>> {
+    let mut checks = Vec::new();
+    for task in tasks {
+        let TaskKind::Stow(stow) = &task.kind else { continue };
+        match stow::link_states(stow) {
+            Ok(states) => checks.extend(states.into_iter().map(|(target, state)| {
+                Ok(LinkCheck { task: task.id.clone(), target, state })
+            })),
+            Err(err) => checks.push(Err(format!("{}: {:#}", task.id, err))),
+        }
+    }
+    checks
+}
@@ -0,0 +1,270 @@
+use crate::config::{OnFailure, Task};
+use crate::exec::{describe_error, Cancelled, Executor, Retry, Termination};
+use crate::graph;
+use crate::privilege::Sudo;
+use crate::tasks::{self, Check};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepStatus {
+    Pending,
+    Running,
+    Completed,
+    /// The check phase found nothing to do, so the task was skipped.
+    AlreadyDone,
+    Failed,
+    /// The run was cancelled while the task was running.
+    Cancelled,
+    /// Not run because a task failed, per its `on_failure` policy.
+    Skipped,
+}
+
+impl StepStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StepStatus::Pending => "pending",
+            StepStatus::Running => "running",
+            StepStatus::Completed => "completed",
+            StepStatus::AlreadyDone => "already_done",
+            StepStatus::Failed => "failed",
+            StepStatus::Cancelled => "cancelled",
+            StepStatus::Skipped => "skipped",
+        }
+    }
+
+    /// Whether the step's work is done, so resuming the run can skip it.
+    pub fn is_done(&self) -> bool {
+        matches!(self, StepStatus::Completed | StepStatus::AlreadyDone)
+    }
+}
+
+/// Receives progress from [`run_tasks`]. The TUI records it into the
+/// stepper and Output pane; the headless CLI prints it.
+pub trait Reporter: Send + Sync {
+    fn task_started(&self, index: usize, task: &Task);
+    fn output(&self, index: usize, line: String);
+    fn task_finished(&self, index: usize, task: &Task, status: StepStatus, error: &[String]);
+    /// A command of the task exited, successfully or not.
+    fn command_finished(&self, _index: usize, _command: &str, _termination: Termination) {}
+}
+
+/// The one-line result for a finished task followed by any error details,
+/// as shown in the Output pane and by the text CLI.
+pub fn status_lines(task: &Task, status: StepStatus, error: &[String]) -> Vec<String> {
+    match (status, error.split_first()) {
+        (StepStatus::Skipped, _) => vec![format!("↷ {} skipped: {}", task.name, error.join("; "))],
+        (_, Some((cause, details))) => {
+            let mut lines = vec![format!("✗ {} failed: {}", task.name, cause)];
+            lines.extend(details.iter().cloned());
+            lines
+        }
+        (StepStatus::AlreadyDone, None) => vec![format!("✓ {} already done, skipping", task.name)],
+        (StepStatus::Cancelled, None) => vec![format!("⊘ {} cancelled", task.name)],
+        (_, None) => vec![format!("✓ {} completed", task.name)],
+    }
+}
+
+pub struct RunOptions {
+    /// Report each task's plan instead of executing anything.
+    pub dry_run: bool,
+    /// How many independent tasks may run at the same time.
+    pub jobs: usize,
+    /// The top-level `on_failure`, for tasks that don't set their own.
+    pub on_failure: OnFailure,
+}
+
+/// Runs `tasks`, starting each one once everything it `depends_on` has
+/// finished and keeping up to `options.jobs` of them in flight. Tasks that
+/// go through sudo never overlap each other, since dnf and apt take a
+/// global lock anyway. Each task is checked first and skipped when already satisfied.
+/// A failed task's `on_failure` decides whether its dependents, or all the
+/// tasks not started yet, are skipped. Once `running` is cleared no new
+/// task starts and the commands still running are killed. Returns the
+/// final status of every task.
+pub fn run_tasks(
+    tasks: &[Task],
+    sudo: Option<Arc<Sudo>>,
+    options: &RunOptions,
+    running: &Arc<AtomicBool>,
+    reporter: Arc<dyn Reporter>,
+) -> Vec<StepStatus> {
+    let _keepalive = sudo.as_ref().map(Sudo::keepalive);
+    let deps = graph::dependencies(tasks);
+    let jobs = if options.dry_run { 1 } else { options.jobs.max(1) };
+    let mut statuses = vec![StepStatus::Pending; tasks.len()];
+
+    thread::scope(|scope| {
+        let (done_tx, done_rx) = mpsc::channel();
+        let mut in_flight = 0;
+        let mut privileged_in_flight = false;
+        // The failed task whose `on_failure = "stop"` ended the run.
+        let mut stopped_by = None;
+
+        loop {
+            while running.load(Ordering::Relaxed) && stopped_by.is_none() && in_flight < jobs {
+                let finished = |j: &usize| !matches!(statuses[*j], StepStatus::Pending | StepStatus::Running);
+                let next = (0..tasks.len()).find(|&i| {
+                    statuses[i] == StepStatus::Pending
+                        && deps[i].iter().all(finished)
+                        && !(privileged_in_flight && tasks[i].kind.needs_sudo())
+                });
+                let Some(i) = next else { break };
+
+                statuses[i] = StepStatus::Running;
+                in_flight += 1;
+                privileged_in_flight |= tasks[i].kind.needs_sudo();
+                reporter.task_started(i, &tasks[i]);
+
+                let done_tx = done_tx.clone();
+                let reporter = Arc::clone(&reporter);
+                let sudo = sudo.clone();
+                let running = Arc::clone(running);
+                scope.spawn(move || {
+                    let (status, error) = run_one(i, &tasks[i], sudo, options.dry_run, running, reporter);
+                    let _ = done_tx.send((i, status, error));
+                });
+            }
+
+            if in_flight == 0 {
+                break;
+            }
+
+            let Ok((i, status, error)) = done_rx.recv() else { break };
+            in_flight -= 1;
+            if tasks[i].kind.needs_sudo() {
+                privileged_in_flight = false;
+            }
+            statuses[i] = status;
+            reporter.task_finished(i, &tasks[i], status, &error);
+
+            if status == StepStatus::Failed {
+                match tasks[i].on_failure.unwrap_or(options.on_failure) {
+                    OnFailure::Stop => stopped_by = stopped_by.or(Some(i)),
+                    OnFailure::Continue => {}
+                    OnFailure::SkipDependents => {
+                        let reason = [format!("{} failed", tasks[i].name)];
+                        for j in graph::dependents(&deps, i) {
+                            if statuses[j] == StepStatus::Pending {
+                                statuses[j] = StepStatus::Skipped;
+                                reporter.task_finished(j, &tasks[j], StepStatus::Skipped, &reason);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(i) = stopped_by {
+            let reason = [format!("the run stopped after {} failed", tasks[i].name)];
+            for j in 0..tasks.len() {
+                if statuses[j] == StepStatus::Pending {
+                    statuses[j] = StepStatus::Skipped;
+                    reporter.task_finished(j, &tasks[j], StepStatus::Skipped, &reason);
+                }
+            }
+        }
+    });
+
+    statuses
+}
+
+fn run_one(
+    index: usize,
+    task: &Task,
+    sudo: Option<Arc<Sudo>>,
+    dry_run: bool,
+    running: Arc<AtomicBool>,
+    reporter: Arc<dyn Reporter>,
+) -> (StepStatus, Vec<String>) {
+    if dry_run {
+        for line in tasks::describe_plan(task) {
+            reporter.output(index, line);
+        }
+        return (StepStatus::Completed, Vec::new());
+    }
+
+    match tasks::check_task(task) {
+        Check::Satisfied => (StepStatus::AlreadyDone, Vec::new()),
+        Check::Pending(pending) => {
+            let result = Executor::new(reporter, index).and_then(|exec| {
+                let exec = exec.with_sudo(sudo).with_cancel(running).with_retry(Retry::of(task));
+                tasks::run_task(&exec, &pending)
+            });
+            match result {
+                Ok(()) => (StepStatus::Completed, Vec::new()),
+                Err(err) if err.downcast_ref::<Cancelled>().is_some() => (StepStatus::Cancelled, Vec::new()),
+                Err(err) => (StepStatus::Failed, describe_error(&err)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use StepStatus::*;
+
+    struct Quiet;
+
+    impl Reporter for Quiet {
+        fn task_started(&self, _: usize, _: &Task) {}
+        fn output(&self, _: usize, _: String) {}
+        fn task_finished(&self, _: usize, _: &Task, _: StepStatus, _: &[String]) {}
+    }
+
+    /// A shell task running `command`; `extra` is appended to its TOML.
+    fn task(id: &str, command: &str, depends_on: &[&str], extra: &str) -> Task {
+        let deps: Vec<String> = depends_on.iter().map(|dep| format!("{:?}", dep)).collect();
+        toml::from_str(&format!(
+            "id = {id:?}\nname = {id:?}\nkind = \"shell\"\ncommand = {command:?}\ndepends_on = [{}]\n{extra}",
+            deps.join(", ")
+        ))
+        .unwrap()
+    }
+
+    /// `fails`, `after` depending on it, `later` depending on `after`, and
+    /// the independent `other`, run one at a time.
+    fn run(on_failure: OnFailure, extra: &str) -> Vec<StepStatus> {
+        let tasks = [
+            task("fails", "exit 1", &[], extra),
+            task("after", "true", &["fails"], ""),
+            task("later", "true", &["after"], ""),
+            task("other", "true", &[], ""),
+        ];
+        let options = RunOptions { dry_run: false, jobs: 1, on_failure };
+        run_tasks(&tasks, None, &options, &Arc::new(AtomicBool::new(true)), Arc::new(Quiet))
+    }
+
+    #[test]
+    fn skip_dependents_skips_everything_downstream() {
+        assert_eq!(run(OnFailure::SkipDependents, ""), [Failed, Skipped, Skipped, Completed]);
+    }
+
+    #[test]
+    fn stop_starts_nothing_else() {
+        assert_eq!(run(OnFailure::Stop, ""), [Failed, Skipped, Skipped, Skipped]);
+    }
+
+    #[test]
+    fn continue_runs_the_dependents_anyway() {
+        assert_eq!(run(OnFailure::Continue, ""), [Failed, Completed, Completed, Completed]);
+    }
+
+    #[test]
+    fn task_policy_overrides_the_default() {
+        assert_eq!(run(OnFailure::Stop, "on_failure = \"continue\""), [Failed, Completed, Completed, Completed]);
+        assert_eq!(run(OnFailure::Continue, "on_failure = \"skip-dependents\""), [Failed, Skipped, Skipped, Completed]);
+    }
+
+    #[test]
+    fn nothing_starts_once_cancelled() {
+        let tasks = [task("a", "true", &[], ""), task("b", "true", &["a"], "")];
+        let options = RunOptions { dry_run: false, jobs: 2, on_failure: OnFailure::default() };
+        let statuses = run_tasks(&tasks, None, &options, &Arc::new(AtomicBool::new(false)), Arc::new(Quiet));
+        assert_eq!(statuses, [Pending, Pending]);
+    }
+}
@@ -0,0 +1,518 @@
+use crate::config::{Task, MAX_RETRY_BACKOFF};
+use crate::engine::Reporter;
+use crate::privilege::{self, Sudo};
+use color_eyre::eyre::eyre;
+use color_eyre::{Report, Result};
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+use tokio::runtime::Runtime;
+
+/// How many trailing stderr lines a [`CommandError`] keeps.
+const STDERR_TAIL_LINES: usize = 10;
+
+/// How often a running command checks whether the run was cancelled.
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+
+/// How long a cancelled command gets to exit after SIGTERM before it is
+/// sent SIGKILL.
+const KILL_GRACE: Duration = Duration::from_secs(5);
+
+/// How a command ended.
+#[derive(Debug, Clone, Copy)]
+pub enum Termination {
+    Code(i32),
+    Signal(i32),
+    /// Stopped after running for the task's `timeout`, in seconds.
+    TimedOut(u64),
+}
+
+impl From<ExitStatus> for Termination {
+    fn from(status: ExitStatus) -> Self {
+        match (status.code(), status.signal()) {
+            (Some(code), _) => Termination::Code(code),
+            (None, Some(signal)) => Termination::Signal(signal),
+            (None, None) => Termination::Code(-1),
+        }
+    }
+}
+
+impl fmt::Display for Termination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Termination::Code(code) => write!(f, "exit code {}", code),
+            Termination::Signal(signal) => write!(f, "killed by signal {}", signal),
+            Termination::TimedOut(secs) => write!(f, "timed out after {}s", secs),
+        }
+    }
+}
+
+/// A command that ran but did not succeed.
+#[derive(Debug)]
+pub struct CommandError {
+    pub command: String,
+    pub termination: Termination,
+    /// The last [`STDERR_TAIL_LINES`] lines the command wrote to stderr.
+    pub stderr_tail: Vec<String>,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.termination {
+            Termination::TimedOut(_) => write!(f, "`{}` {}", self.command, self.termination),
+            _ => write!(f, "`{}` failed with {}", self.command, self.termination),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// The run was cancelled while a command of the task was running, or
+/// before the next one could start.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Renders an error from a task as lines for the Output pane and summary,
+/// expanding the stderr tail when the cause is a [`CommandError`].
+pub fn describe_error(err: &Report) -> Vec<String> {
+    match err.downcast_ref::<CommandError>() {
+        Some(cmd_err) => {
+            let mut lines = vec![cmd_err.to_string()];
+            lines.extend(cmd_err.stderr_tail.iter().map(|l| format!("  | {}", l)));
+            lines
+        }
+        None => vec![format!("{:#}", err)],
+    }
+}
+
+/// How long a command may run and how often it is retried, from a task's
+/// `timeout`, `retries` and `retry_backoff`.
+#[derive(Clone, Copy, Default)]
+pub struct Retry {
+    pub timeout: Option<Duration>,
+    pub retries: u32,
+    /// The wait before the first retry, doubled for each one after up to
+    /// [`MAX_RETRY_BACKOFF`].
+    pub backoff: Duration,
+}
+
+impl Retry {
+    pub fn of(task: &Task) -> Self {
+        Self {
+            timeout: task.timeout.map(Duration::from_secs),
+            retries: task.retries,
+            backoff: Duration::from_secs(task.retry_backoff),
+        }
+    }
+}
+
+/// The wait before the attempt after one that waited `backoff`: twice as
+/// long, up to [`MAX_RETRY_BACKOFF`].
+fn next_backoff(backoff: Duration) -> Duration {
+    backoff.saturating_mul(2).min(Duration::from_secs(MAX_RETRY_BACKOFF))
+}
+
+/// Runs the shell commands of one task and streams their stdout/stderr to
+/// the [`Reporter`] line by line while they are still running.
+pub struct Executor {
+    runtime: Runtime,
+    reporter: Arc<dyn Reporter>,
+    task: usize,
+    sudo: Option<Arc<Sudo>>,
+    /// Cleared to cancel the run; see [`Executor::with_cancel`].
+    running: Option<Arc<AtomicBool>>,
+    retry: Retry,
+    /// [`KILL_GRACE`], shortened by the tests.
+    kill_grace: Duration,
+}
+
+impl Executor {
+    pub fn new(reporter: Arc<dyn Reporter>, task: usize) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        Ok(Self {
+            runtime,
+            reporter,
+            task,
+            sudo: None,
+            running: None,
+            retry: Retry::default(),
+            kill_grace: KILL_GRACE,
+        })
+    }
+
+    /// Enables [`Executor::run_privileged`] with an already validated credential.
+    pub fn with_sudo(mut self, sudo: Option<Arc<Sudo>>) -> Self {
+        self.sudo = sudo;
+        self
+    }
+
+    /// Stops the running command, and every process it started, once
+    /// `running` is cleared.
+    pub fn with_cancel(mut self, running: Arc<AtomicBool>) -> Self {
+        self.running = Some(running);
+        self
+    }
+
+    /// Times out and retries failing commands as `retry` says.
+    pub fn with_retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn run(&self, cmd: &str) -> Result<()> {
+        let command = || {
+            let mut command = Command::new("sh");
+            command.args(["-c", cmd]);
+            command
+        };
+        self.runtime.block_on(self.retrying(command, cmd, false))
+    }
+
+    /// Runs `cmd` as root via `sudo -n`, relying on the timestamp that
+    /// [`Sudo`] keeps fresh. When already root the command runs directly.
+    pub fn run_privileged(&self, cmd: &str) -> Result<()> {
+        if privilege::is_root() {
+            return self.run(cmd);
+        }
+
+        let sudo = self
+            .sudo
+            .as_ref()
+            .ok_or_else(|| eyre!("`{}` needs root but no sudo password was provided", cmd))?;
+        sudo.refresh()?;
+
+        let command = || {
+            let mut command = Command::new("sudo");
+            command.args(["-n", "--", "sh", "-c", cmd]);
+            command
+        };
+        self.runtime.block_on(self.retrying(command, &format!("sudo {}", cmd), true))
+    }
+
+    /// Reports a line of task output for work done in-process rather than
+    /// by a command.
+    pub fn report(&self, line: String) {
+        self.reporter.output(self.task, line);
+    }
+
+    fn cancelled(&self) -> bool {
+        self.running.as_ref().is_some_and(|running| !running.load(Ordering::Relaxed))
+    }
+
+    async fn until_cancelled(&self) {
+        match &self.running {
+            Some(running) => {
+                while running.load(Ordering::Relaxed) {
+                    tokio::time::sleep(CANCEL_POLL).await;
+                }
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Sends `signal` to the process group `pgid`. The commands sudo starts
+    /// run as root and can't be signalled by us, so for a privileged command
+    /// the signal is sent again through `sudo -n kill`.
+    async fn signal_group(&self, pgid: i32, signal: i32, privileged: bool) {
+        // SAFETY: kill has no preconditions; a group that is already gone
+        // only makes it fail with ESRCH.
+        unsafe { libc::kill(-pgid, signal) };
+        if privileged && !privilege::is_root() {
+            let _ = Command::new("sudo")
+                .args(["-n", "kill", &format!("-{}", signal), "--", &format!("-{}", pgid)])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .await;
+        }
+    }
+
+    /// Runs the command built by `command` until it succeeds or the retries
+    /// run out, reporting every failed attempt.
+    async fn retrying(&self, command: impl Fn() -> Command, display: &str, privileged: bool) -> Result<()> {
+        let attempts = self.retry.retries + 1;
+        let mut backoff = self.retry.backoff;
+        let mut attempt = 1;
+        loop {
+            let result = self.stream(command(), display, privileged).await;
+            let Err(err) = &result else { return result };
+            if attempts == 1 || err.downcast_ref::<CommandError>().is_none() {
+                return result;
+            }
+            if attempt == attempts {
+                self.report(format!("{} (attempt {} of {}), giving up", err, attempt, attempts));
+                return result;
+            }
+            self.report(format!(
+                "{} (attempt {} of {}), retrying in {}s",
+                err,
+                attempt,
+                attempts,
+                backoff.as_secs()
+            ));
+            tokio::select! {
+                () = tokio::time::sleep(backoff) => {}
+                () = self.until_cancelled() => return Err(Cancelled.into()),
+            }
+            attempt += 1;
+            backoff = next_backoff(backoff);
+        }
+    }
+
+    async fn stream(&self, mut command: Command, display: &str, privileged: bool) -> Result<()> {
+        if self.cancelled() {
+            return Err(Cancelled.into());
+        }
+
+        // The command leads its own process group, so cancelling reaches
+        // everything it starts, including sudo and its children.
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .kill_on_drop(true)
+            .spawn()?;
+        let pgid = child.id().expect("the child has not been waited for") as i32;
+
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+
+        let run = async {
+            tokio::join!(child.wait(), self.forward_lines(stdout), self.forward_lines(stderr))
+        };
+        tokio::pin!(run);
+        let timeout = async {
+            match self.retry.timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+        let mut cancelled = false;
+        let mut timed_out = None;
+        let (status, _, stderr_tail) = tokio::select! {
+            result = &mut run => result,
+            () = self.until_cancelled() => {
+                cancelled = true;
+                self.report(format!("Cancelling `{}`...", display));
+                self.terminate(&mut run, pgid, display, privileged).await
+            }
+            () = timeout => {
+                let secs = self.retry.timeout.unwrap_or_default().as_secs();
+                timed_out = Some(Termination::TimedOut(secs));
+                self.report(format!("`{}` is still running after {}s, stopping it", display, secs));
+                self.terminate(&mut run, pgid, display, privileged).await
+            }
+        };
+
+        let status = status?;
+        let termination = timed_out.unwrap_or(status.into());
+        self.reporter.command_finished(self.task, display, termination);
+        if cancelled {
+            return Err(Cancelled.into());
+        }
+        if timed_out.is_some() || !status.success() {
+            return Err(CommandError {
+                command: display.to_string(),
+                termination,
+                stderr_tail: stderr_tail.into(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Sends SIGTERM to the command's process group and SIGKILL when it is
+    /// still running [`KILL_GRACE`] later, then waits for `run` to finish.
+    async fn terminate<F: Future + Unpin>(&self, mut run: F, pgid: i32, display: &str, privileged: bool) -> F::Output {
+        self.signal_group(pgid, libc::SIGTERM, privileged).await;
+        match tokio::time::timeout(self.kill_grace, &mut run).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.report(format!("`{}` ignored SIGTERM, killing it", display));
+                self.signal_group(pgid, libc::SIGKILL, privileged).await;
+                run.await
+            }
+        }
+    }
+
+    /// Reports every line read from `reader` as task output and returns the
+    /// last [`STDERR_TAIL_LINES`] of them.
+    async fn forward_lines<R: AsyncRead + Unpin>(&self, reader: R) -> VecDeque<String> {
+        let mut reader = BufReader::new(reader);
+        let mut buf = Vec::new();
+        let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            let line = String::from_utf8_lossy(&buf);
+            // Progress bars (dnf, curl, cargo) redraw themselves with '\r'; only
+            // the last frame is worth showing.
+            let line = line.trim_end_matches(['\n', '\r']).rsplit('\r').next().unwrap_or_default();
+            self.reporter.output(self.task, line.to_string());
+            if tail.len() == STDERR_TAIL_LINES {
+                tail.pop_front();
+            }
+            tail.push_back(line.to_string());
+        }
+        tail
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::StepStatus;
+    use std::fs;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    /// Records what the executor reports.
+    #[derive(Default)]
+    struct Recorder {
+        lines: Mutex<Vec<String>>,
+        finished: Mutex<Vec<String>>,
+    }
+
+    impl Reporter for Recorder {
+        fn task_started(&self, _: usize, _: &Task) {}
+        fn output(&self, _: usize, line: String) {
+            self.lines.lock().unwrap().push(line);
+        }
+        fn task_finished(&self, _: usize, _: &Task, _: StepStatus, _: &[String]) {}
+        fn command_finished(&self, _: usize, _: &str, termination: Termination) {
+            self.finished.lock().unwrap().push(termination.to_string());
+        }
+    }
+
+    fn executor(retry: Retry) -> (Executor, Arc<Recorder>) {
+        let recorder = Arc::new(Recorder::default());
+        let exec = Executor::new(Arc::clone(&recorder) as Arc<dyn Reporter>, 0).unwrap().with_retry(retry);
+        (exec, recorder)
+    }
+
+    fn retries(retries: u32) -> Retry {
+        Retry { timeout: None, retries, backoff: Duration::ZERO }
+    }
+
+    fn command_error(result: Result<()>) -> CommandError {
+        let err = result.expect_err("the command should fail");
+        match err.downcast::<CommandError>() {
+            Ok(err) => err,
+            Err(err) => panic!("not a CommandError: {:#}", err),
+        }
+    }
+
+    #[test]
+    fn streams_output_and_keeps_the_stderr_tail() {
+        let (exec, recorder) = executor(Retry::default());
+        let err = command_error(exec.run("echo out; echo err >&2; exit 3"));
+        assert!(matches!(err.termination, Termination::Code(3)));
+        assert_eq!(err.stderr_tail, ["err"]);
+        let mut lines = recorder.lines.lock().unwrap().clone();
+        lines.sort();
+        assert_eq!(lines, ["err", "out"]);
+        assert_eq!(*recorder.finished.lock().unwrap(), ["exit code 3"]);
+    }
+
+    #[test]
+    fn retries_until_the_attempts_run_out() {
+        let (exec, recorder) = executor(retries(2));
+        let err = command_error(exec.run("echo try; exit 1"));
+        assert!(matches!(err.termination, Termination::Code(1)));
+
+        let lines = recorder.lines.lock().unwrap();
+        assert_eq!(lines.iter().filter(|line| *line == "try").count(), 3);
+        assert!(lines.contains(&"`echo try; exit 1` failed with exit code 1 (attempt 1 of 3), retrying in 0s".to_string()));
+        assert!(lines.contains(&"`echo try; exit 1` failed with exit code 1 (attempt 3 of 3), giving up".to_string()));
+        assert_eq!(recorder.finished.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn a_later_attempt_can_succeed() {
+        let counter = std::env::temp_dir().join(format!("dot-setup-test-{}-attempts", std::process::id()));
+        let _ = fs::remove_file(&counter);
+        let (exec, recorder) = executor(retries(5));
+        let cmd = format!("echo x >> {0}; [ $(wc -l < {0}) -ge 2 ]", counter.display());
+        exec.run(&cmd).unwrap();
+        assert_eq!(fs::read_to_string(&counter).unwrap().lines().count(), 2);
+        assert_eq!(*recorder.finished.lock().unwrap(), ["exit code 1", "exit code 0"]);
+    }
+
+    #[test]
+    fn no_retries_without_a_command_error() {
+        let (exec, recorder) = executor(retries(3));
+        exec.run("true").unwrap();
+        assert_eq!(recorder.finished.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let max = Duration::from_secs(MAX_RETRY_BACKOFF);
+        assert_eq!(next_backoff(Duration::ZERO), Duration::ZERO);
+        assert_eq!(next_backoff(Duration::from_secs(5)), Duration::from_secs(10));
+        assert_eq!(next_backoff(Duration::from_secs(MAX_RETRY_BACKOFF - 1)), max);
+        assert_eq!(next_backoff(Duration::MAX), max);
+    }
+
+    #[test]
+    fn timeout_stops_the_command() {
+        let retry = Retry { timeout: Some(Duration::from_millis(200)), retries: 0, backoff: Duration::ZERO };
+        let (exec, recorder) = executor(retry);
+        let started = Instant::now();
+        let err = command_error(exec.run("sleep 30"));
+        assert!(matches!(err.termination, Termination::TimedOut(_)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!recorder.lines.lock().unwrap().iter().any(|line| line.contains("ignored SIGTERM")));
+    }
+
+    #[test]
+    fn timeout_kills_a_command_that_ignores_sigterm() {
+        let retry = Retry { timeout: Some(Duration::from_millis(200)), retries: 0, backoff: Duration::ZERO };
+        let (mut exec, recorder) = executor(retry);
+        exec.kill_grace = Duration::from_millis(300);
+        let started = Instant::now();
+        let err = command_error(exec.run("trap '' TERM; sleep 30"));
+        assert!(matches!(err.termination, Termination::TimedOut(_)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let lines = recorder.lines.lock().unwrap();
+        assert!(lines.contains(&"`trap '' TERM; sleep 30` ignored SIGTERM, killing it".to_string()), "{:?}", lines);
+    }
+
+    #[test]
+    fn cancelling_stops_the_command_and_the_retries() {
+        let running = Arc::new(AtomicBool::new(true));
+        let (exec, _) = executor(retries(3));
+        let exec = exec.with_cancel(Arc::clone(&running));
+        let cancel = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            running.store(false, Ordering::Relaxed);
+        });
+        let started = Instant::now();
+        let err = exec.run("sleep 30").expect_err("the command should be cancelled");
+        cancel.join().unwrap();
+        assert!(err.downcast_ref::<Cancelled>().is_some(), "{:#}", err);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,153 @@
+use crate::config::Task;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+
+/// Checks that every `depends_on` entry names a declared task and that the
+/// dependencies don't form a cycle.
+pub fn validate(tasks: &[Task]) -> Result<()> {
+    for task in tasks {
+        for dep in &task.depends_on {
+            if !tasks.iter().any(|t| &t.id == dep) {
+                return Err(eyre!("task `{}` depends on unknown task `{}`", task.id, dep));
+            }
+        }
+    }
+
+    let deps = dependencies(tasks);
+    let mut marks = vec![Mark::Unvisited; tasks.len()];
+    let mut path = Vec::new();
+    for i in 0..tasks.len() {
+        if let Some(cycle) = find_cycle(i, &deps, &mut marks, &mut path) {
+            let names: Vec<&str> = cycle.iter().map(|&j| tasks[j].id.as_str()).collect();
+            return Err(eyre!("dependency cycle: {}", names.join(" -> ")));
+        }
+    }
+    Ok(())
+}
+
+/// For each task, the indices of the tasks in `tasks` it depends on.
+/// Dependencies that aren't part of `tasks` (e.g. deselected in the TUI)
+/// are treated as already satisfied.
+pub fn dependencies(tasks: &[Task]) -> Vec<Vec<usize>> {
+    tasks
+        .iter()
+        .map(|task| {
+            task.depends_on
+                .iter()
+                .filter_map(|dep| tasks.iter().position(|t| &t.id == dep))
+                .collect()
+        })
+        .collect()
+}
+
+/// The tasks that depend on task `of`, directly or through other tasks.
+pub fn dependents(deps: &[Vec<usize>], of: usize) -> Vec<usize> {
+    let mut found = vec![of];
+    while let Some(next) = (0..deps.len()).find(|j| !found.contains(j) && deps[*j].iter().any(|d| found.contains(d))) {
+        found.push(next);
+    }
+    found.remove(0);
+    found
+}
+
+/// How many dependency levels sit above each task; roots are at depth 0.
+/// Expects an acyclic graph.
+pub fn depths(deps: &[Vec<usize>]) -> Vec<usize> {
+    fn depth(i: usize, deps: &[Vec<usize>], memo: &mut [Option<usize>]) -> usize {
+        if let Some(d) = memo[i] {
+            return d;
+        }
+        let d = deps[i].iter().map(|&j| depth(j, deps, memo) + 1).max().unwrap_or(0);
+        memo[i] = Some(d);
+        d
+    }
+
+    let mut memo = vec![None; deps.len()];
+    (0..deps.len()).map(|i| depth(i, deps, &mut memo)).collect()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mark {
+    Unvisited,
+    InProgress,
+    Done,
+}
+
+/// Depth-first search that returns the first cycle reachable from `i`,
+/// starting and ending at the same task.
+fn find_cycle(i: usize, deps: &[Vec<usize>], marks: &mut [Mark], path: &mut Vec<usize>) -> Option<Vec<usize>> {
+    match marks[i] {
+        Mark::Done => return None,
+        Mark::InProgress => {
+            let start = path.iter().position(|&j| j == i).unwrap_or(0);
+            let mut cycle = path[start..].to_vec();
+            cycle.push(i);
+            return Some(cycle);
+        }
+        Mark::Unvisited => {}
+    }
+
+    marks[i] = Mark::InProgress;
+    path.push(i);
+    for &dep in &deps[i] {
+        if let Some(cycle) = find_cycle(dep, deps, marks, path) {
+            return Some(cycle);
+        }
+    }
+    path.pop();
+    marks[i] = Mark::Done;
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(id: &str, depends_on: &[&str]) -> Task {
+        let deps: Vec<String> = depends_on.iter().map(|dep| format!("{:?}", dep)).collect();
+        toml::from_str(&format!(
+            "id = {id:?}\nname = {id:?}\nkind = \"shell\"\ncommand = \"true\"\ndepends_on = [{}]",
+            deps.join(", ")
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn acyclic_graph() {
+        let tasks = [task("repos", &[]), task("dnf", &["repos"]), task("terra", &["repos", "dnf"]), task("stow", &[])];
+        validate(&tasks).unwrap();
+
+        let deps = dependencies(&tasks);
+        assert_eq!(deps, [vec![], vec![0], vec![0, 1], vec![]]);
+        assert_eq!(depths(&deps), [0, 1, 2, 0]);
+        assert_eq!(dependents(&deps, 0), [1, 2]);
+        assert_eq!(dependents(&deps, 1), [2]);
+        assert!(dependents(&deps, 3).is_empty());
+    }
+
+    #[test]
+    fn dependencies_outside_the_selection_are_ignored() {
+        let tasks = [task("terra", &["repos"])];
+        assert_eq!(dependencies(&tasks), [Vec::<usize>::new()]);
+    }
+
+    #[test]
+    fn self_loop() {
+        let err = validate(&[task("a", &["a"])]).unwrap_err();
+        assert_eq!(err.to_string(), "dependency cycle: a -> a");
+    }
+
+    #[test]
+    fn three_cycle() {
+        let tasks = [task("a", &["c"]), task("b", &["a"]), task("c", &["b"]), task("d", &["a"])];
+        let err = validate(&tasks).unwrap_err();
+        assert_eq!(err.to_string(), "dependency cycle: a -> c -> b -> a");
+    }
+
+    #[test]
+    fn unknown_dependency() {
+        let tasks = [task("a", &[]), task("b", &["missing"])];
+        let err = validate(&tasks).unwrap_err();
+        assert_eq!(err.to_string(), "task `b` depends on unknown task `missing`");
+    }
+}
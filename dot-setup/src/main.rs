@@ -6,28 +6,53 @@ use crossterm::{
 };
 use ratatui::{
     backend::CrosstermBackend,
-    layout::{Constraint, Direction, Layout},
+    layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Style},
     text::{Line, Span},
-    widgets::{Block, Borders, List, ListItem, Paragraph},
+    widgets::{Block, Borders, Clear, Gauge, List, ListItem, ListState, Paragraph, Wrap},
     Frame, Terminal,
 };
-use serde::Deserialize;
-use std::fs;
+use clap::Parser;
+use std::collections::{BTreeMap, BTreeSet};
 use std::io;
 use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::process::ExitCode;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+mod check;
+mod cli;
+mod config;
+mod distro;
+mod doctor;
+mod engine;
+mod exec;
+mod graph;
+mod privilege;
+mod runlog;
+mod selection;
+mod state;
+mod stow;
+mod tasks;
+mod template;
+
+use cli::{Cli, Commands};
+use config::{load_config, ConfigSource, LoadOptions, OnFailure, Resolution, Task, TaskKind};
+use engine::{Reporter, RunOptions, StepStatus};
+use privilege::Sudo;
+use runlog::{Elapsed, Resolved, RunLog, Summary};
+use selection::{Row, Selection};
+use zeroize::Zeroizing;
 
 #[derive(PartialEq, Clone, Copy)]
 enum AppState {
     Selection = 0,
-    GettingPassword = 1,
-    Running = 2,
-    Done = 3,
+    Conflicts = 1,
+    GettingPassword = 2,
+    Running = 3,
+    Done = 4,
 }
 
 impl AppState {
@@ -38,102 +63,37 @@ impl AppState {
     fn from_usize(val: usize) -> Self {
         match val {
             0 => AppState::Selection,
-            1 => AppState::GettingPassword,
-            2 => AppState::Running,
+            1 => AppState::Conflicts,
+            2 => AppState::GettingPassword,
+            3 => AppState::Running,
             _ => AppState::Done,
         }
     }
 }
 
-#[derive(Deserialize, Clone)]
-struct Config {
-    repositories: Repositories,
-    packages: Packages,
-    commands: Commands,
-}
-
-#[derive(Deserialize, Clone)]
-struct Repositories {
-    #[serde(rename = "rpm_fusion_free")]
-    rpm_fusion_free: String,
-    #[serde(rename = "rpm_fusion_nonfree")]
-    rpm_fusion_nonfree: String,
-    docker: String,
-    terra: String,
-}
-
-#[derive(Deserialize, Clone)]
-struct Packages {
-    #[serde(rename = "dnf")]
-    dnf: PackageGroup,
-    docker: PackageGroup,
-    flatpak: FlatpakGroup,
-    terra: PackageGroup,
-    homebrew: HomebrewGroup,
-    cargo: PackageGroup,
-    opencode: OpenCodeGroup,
-}
-
-#[derive(Deserialize, Clone)]
-struct PackageGroup {
-    #[allow(dead_code)]
-    description: String,
-    packages: Vec<String>,
-    #[serde(default)]
-    enable_service: bool,
-}
-
-#[derive(Deserialize, Clone)]
-struct FlatpakGroup {
-    #[allow(dead_code)]
-    description: String,
-    remote: String,
-    apps: Vec<String>,
-}
-
-#[derive(Deserialize, Clone)]
-struct HomebrewGroup {
-    #[allow(dead_code)]
-    description: String,
-    #[serde(rename = "install_script")]
-    install_script: String,
-    packages: Vec<String>,
-}
-
-#[derive(Deserialize, Clone)]
-struct OpenCodeGroup {
-    #[allow(dead_code)]
-    description: String,
-    url: String,
-}
-
-#[derive(Deserialize, Clone)]
-struct Commands {
-    update: String,
-    #[serde(rename = "shell_init")]
-    shell_init: String,
-}
-
-#[derive(Clone)]
-struct Task {
-    id: String,
+struct Step {
     name: String,
-    enabled: bool,
-    is_install: bool,
+    status: StepStatus,
+    error: Vec<String>,
+    /// Indices of the steps this one waits for.
+    depends_on: Vec<usize>,
+    /// Dependency level, used to indent the stepper.
+    depth: usize,
+    /// The step's own output, shown when it is selected in the stepper.
+    log: Vec<String>,
+    started: Option<Instant>,
+    duration: Option<Duration>,
 }
 
-#[derive(Clone, PartialEq)]
-enum StepStatus {
-    Pending,
-    Running,
-    Completed,
-    Failed,
+impl Step {
+    /// How long the step took, or has been running so far.
+    fn elapsed(&self) -> Option<Duration> {
+        self.duration.or_else(|| self.started.map(|started| started.elapsed()))
+    }
 }
 
-struct Step {
-    name: String,
-    status: StepStatus,
-}
+/// Narrowest and widest the stepper sidebar can be resized to.
+const SIDEBAR_WIDTH: (u16, u16) = (20, 100);
 
 struct App {
     state: Arc<AtomicUsize>,
@@ -142,40 +102,109 @@ struct App {
     output: Arc<Mutex<Vec<String>>>,
     steps: Arc<Mutex<Vec<Step>>>,
     current_step: Arc<AtomicUsize>,
-    scroll: u16,
-    sudo_password: String,
+    /// The step whose log the Output pane shows; `None` shows every step.
+    viewed_step: Option<usize>,
+    /// How many lines the Output pane is scrolled up from the bottom; 0
+    /// follows new output.
+    scroll_back: usize,
+    /// Width of the stepper sidebar, changed with `<` and `>`.
+    sidebar_width: u16,
+    run_started: Option<Instant>,
+    sudo: Option<Arc<Sudo>>,
+    password_error: Option<String>,
     running: Arc<AtomicBool>,
-    config: Config,
+    dry_run: bool,
+    jobs: usize,
+    /// The top-level `on_failure` of the loaded config.
+    on_failure: OnFailure,
+    distro: String,
+    /// The applied profile and every profile config.toml declares.
+    profile: Option<String>,
+    profiles: Vec<String>,
+    /// Why the last profile switch failed, shown in the header.
+    profile_error: Option<String>,
+    /// The `--config` and `--profile` flags, reused when switching profiles.
+    load_options: LoadOptions,
+    /// Where the config was read from, shown in the header and recorded in
+    /// run logs.
+    config_source: ConfigSource,
+    /// Task toggles and unchecked packages, persisted between launches.
+    selection: Selection,
+    /// Ids of the tasks whose packages are shown in the selection tree.
+    expanded: BTreeSet<String>,
+    filter: String,
+    /// Whether keystrokes go to the filter box.
+    filtering: bool,
+    /// Files in the way of the stow links, shown before the run starts.
+    conflicts: Vec<stow::Conflict>,
+    /// The choice made for each conflicting target.
+    resolutions: BTreeMap<PathBuf, Resolution>,
+    conflict_index: usize,
+    /// Set once the Conflicts screen has been confirmed for this run.
+    conflicts_reviewed: bool,
+    /// The last run when it didn't finish, offered for resuming until
+    /// another run starts.
+    resume: Option<Summary>,
+    /// Tasks to run instead of the selection, when resuming or retrying.
+    only: Option<Vec<Task>>,
+    /// The tasks of the current or last run, in step order.
+    run_tasks: Vec<Task>,
+    /// Set while asking whether to kill a running package transaction.
+    confirm_cancel: bool,
 }
 
 impl App {
-    fn new() -> Self {
-        let config = load_config().expect("Failed to load config");
+    fn new(load_options: LoadOptions) -> Result<Self> {
+        let selection = Selection::load();
+        let config = match (&load_options.profile, &selection.profile) {
+            (None, Some(remembered)) => {
+                let remembered = LoadOptions { profile: Some(remembered.clone()), ..load_options.clone() };
+                // A remembered profile may have been removed from config.toml since.
+                load_config(&remembered).or_else(|_| load_config(&load_options))
+            }
+            _ => load_config(&load_options),
+        }?;
         
-        let tasks = vec![
-            Task { id: "repos".to_string(), name: "Add Repositories (RPM Fusion, Docker, Terra)".to_string(), enabled: true, is_install: true },
-            Task { id: "dnf".to_string(), name: "Install System Packages".to_string(), enabled: true, is_install: true },
-            Task { id: "docker".to_string(), name: "Install Docker".to_string(), enabled: true, is_install: true },
-            Task { id: "flatpak".to_string(), name: "Install Flatpak Apps".to_string(), enabled: true, is_install: true },
-            Task { id: "homebrew".to_string(), name: "Install Homebrew".to_string(), enabled: true, is_install: true },
-            Task { id: "opencode".to_string(), name: "Install OpenCode".to_string(), enabled: true, is_install: true },
-            Task { id: "cargo".to_string(), name: "Install Cargo Packages".to_string(), enabled: true, is_install: true },
-            Task { id: "terra".to_string(), name: "Install Terra Extras".to_string(), enabled: true, is_install: true },
-            Task { id: "stow".to_string(), name: "Stow Dotfiles".to_string(), enabled: true, is_install: false },
-        ];
-
-        Self {
+        let mut tasks = config.tasks;
+        let jobs = config.jobs;
+        selection.restore(&mut tasks);
+
+        Ok(Self {
             state: Arc::new(AtomicUsize::new(0)),
             selected_index: 0,
             tasks,
             output: Arc::new(Mutex::new(vec!["Welcome to dot-setup".to_string()])),
             steps: Arc::new(Mutex::new(Vec::new())),
             current_step: Arc::new(AtomicUsize::new(0)),
-            scroll: 0,
-            sudo_password: String::new(),
+            viewed_step: None,
+            scroll_back: 0,
+            sidebar_width: 40,
+            run_started: None,
+            sudo: None,
+            password_error: None,
             running: Arc::new(AtomicBool::new(true)),
-            config,
-        }
+            dry_run: false,
+            jobs,
+            on_failure: config.on_failure,
+            distro: distro::detect().pretty_name,
+            profile: config.profile,
+            profiles: config.profiles.into_keys().collect(),
+            config_source: config.source.clone(),
+            profile_error: None,
+            load_options,
+            selection,
+            expanded: BTreeSet::new(),
+            filter: String::new(),
+            filtering: false,
+            conflicts: Vec::new(),
+            resolutions: BTreeMap::new(),
+            conflict_index: 0,
+            conflicts_reviewed: false,
+            resume: runlog::resumable(),
+            only: None,
+            run_tasks: Vec::new(),
+            confirm_cancel: false,
+        })
     }
 
     fn get_state(&self) -> AppState {
@@ -186,14 +215,129 @@ impl App {
         self.state.store(new_state.as_usize(), Ordering::Relaxed);
     }
 
+    /// Enabled tasks with their unchecked packages removed. A package group
+    /// whose packages are all unchecked is left out entirely.
     fn get_enabled_tasks(&self) -> Vec<Task> {
-        self.tasks.iter().filter(|t| t.enabled).cloned().collect()
+        self.tasks.iter().filter(|t| t.enabled).filter_map(|t| self.apply_selection(t)).collect()
+    }
+
+    fn apply_selection(&self, t: &Task) -> Option<Task> {
+        let task = self.selection.apply(t);
+        let emptied = task.kind.items().is_empty() && !t.kind.items().is_empty();
+        (!emptied).then_some(task)
+    }
+
+    /// Switches to the next profile declared in config.toml and reloads
+    /// the tasks with it.
+    fn cycle_profile(&mut self) {
+        let next = match &self.profile {
+            Some(current) => self.profiles.iter().skip_while(|p| *p != current).nth(1),
+            None => None,
+        };
+        let Some(next) = next.or(self.profiles.first()).cloned() else { return };
+
+        match load_config(&LoadOptions { profile: Some(next.clone()), ..self.load_options.clone() }) {
+            Ok(config) => {
+                self.tasks = config.tasks;
+                self.jobs = config.jobs;
+                self.on_failure = config.on_failure;
+                self.selection.restore(&mut self.tasks);
+                self.profile = Some(next);
+                self.profile_error = None;
+                self.selected_index = 0;
+            }
+            Err(err) => self.profile_error = Some(format!("profile `{}`: {:#}", next, err)),
+        }
+    }
+
+    fn rows(&self) -> Vec<Row> {
+        selection::rows(&self.tasks, &self.expanded, &self.filter)
+    }
+
+    fn selected_row(&self) -> Row {
+        let rows = self.rows();
+        rows.get(self.selected_index).cloned().unwrap_or(Row::Confirm)
+    }
+
+    /// Toggles the task or package under the cursor, or starts the run
+    /// when the cursor is on Confirm.
+    fn activate(&mut self) {
+        match self.selected_row() {
+            Row::Task(i) => self.tasks[i].enabled = !self.tasks[i].enabled,
+            Row::Item(i, item) => self.selection.toggle_item(&self.tasks[i], &item),
+            Row::Confirm => {
+                self.running.store(true, Ordering::Relaxed);
+                self.conflicts_reviewed = false;
+                self.only = None;
+                self.start_tasks();
+            }
+        }
+    }
+
+    /// The unfinished steps of the last run that are still in config.toml,
+    /// with the packages unchecked now removed.
+    fn resume_tasks(&self) -> Vec<Task> {
+        let Some(run) = &self.resume else { return Vec::new() };
+        let ids: BTreeSet<&str> = run.unfinished().map(|step| step.id.as_str()).collect();
+        self.tasks
+            .iter()
+            .filter(|t| ids.contains(t.id.as_str()))
+            .filter_map(|t| self.apply_selection(t))
+            .collect()
+    }
+
+    /// Runs the unfinished steps of the last run again.
+    fn resume(&mut self) {
+        let tasks = self.resume_tasks();
+        if tasks.is_empty() {
+            return;
+        }
+        self.running.store(true, Ordering::Relaxed);
+        self.conflicts_reviewed = false;
+        self.only = Some(tasks);
+        self.start_tasks();
+    }
+
+    /// Runs the steps of the run that just ended that failed or never
+    /// started, reusing its sudo credential and conflict choices.
+    fn retry(&mut self) {
+        let tasks: Vec<Task> = {
+            let steps = self.steps.lock().unwrap();
+            self.run_tasks
+                .iter()
+                .zip(steps.iter())
+                .filter(|(_, step)| !step.status.is_done())
+                .map(|(task, _)| task.clone())
+                .collect()
+        };
+        if tasks.is_empty() {
+            return;
+        }
+        self.running.store(true, Ordering::Relaxed);
+        self.conflicts_reviewed = true;
+        self.only = Some(tasks);
+        self.start_tasks();
     }
 
-    fn toggle_task(&mut self) {
-        if self.selected_index < self.tasks.len() {
-            self.tasks[self.selected_index].enabled = !self.tasks[self.selected_index].enabled;
+    /// Shows or hides the packages of the task under the cursor, keeping
+    /// the cursor on that task.
+    fn set_expanded(&mut self, expand: bool) {
+        let i = match self.selected_row() {
+            Row::Task(i) | Row::Item(i, _) => i,
+            Row::Confirm => return,
+        };
+        let id = self.tasks[i].id.clone();
+        if expand {
+            self.expanded.insert(id);
+        } else {
+            self.expanded.remove(&id);
         }
+        self.selected_index = self.rows().iter().position(|row| *row == Row::Task(i)).unwrap_or(0);
+    }
+
+    fn set_filter(&mut self, filter: String) {
+        self.filter = filter;
+        self.selected_index = 0;
     }
 
     fn get_password(&mut self) {
@@ -203,254 +347,283 @@ impl App {
         out.push("Enter sudo password:".to_string());
     }
 
+    /// Validates the typed password with sudo before any task starts; on
+    /// failure the user stays on the password prompt.
+    fn submit_password(&mut self, password: Zeroizing<String>) {
+        match Sudo::validate(password) {
+            Ok(sudo) => {
+                self.sudo = Some(Arc::new(sudo));
+                self.password_error = None;
+                self.running.store(true, Ordering::Relaxed);
+                self.start_tasks();
+            }
+            Err(err) => {
+                self.password_error = Some(err.to_string());
+            }
+        }
+    }
+
+    /// Names of the running steps that are dnf or apt transactions.
+    fn running_transactions(&self) -> Vec<String> {
+        let steps = self.steps.lock().unwrap();
+        steps
+            .iter()
+            .zip(&self.run_tasks)
+            .filter(|(step, task)| step.status == StepStatus::Running && task.kind.is_package_transaction())
+            .map(|(step, _)| step.name.clone())
+            .collect()
+    }
+
+    /// Cancels the run, asking first when that would kill a package
+    /// transaction.
+    fn request_cancel(&mut self) {
+        if self.running_transactions().is_empty() {
+            self.cancel();
+        } else {
+            self.confirm_cancel = true;
+        }
+    }
+
+    /// Stops scheduling tasks and kills the commands still running.
+    fn cancel(&mut self) {
+        self.confirm_cancel = false;
+        if self.running.swap(false, Ordering::Relaxed) {
+            self.output.lock().unwrap().push("Cancelling...".to_string());
+        }
+    }
+
+    /// Moves the stepper selection by `delta`. The entry above the first
+    /// step shows the output of every step.
+    fn view_step(&mut self, delta: isize) {
+        let steps = self.steps.lock().unwrap().len();
+        let position = self.viewed_step.map_or(0, |i| i + 1);
+        let position = position.saturating_add_signed(delta).min(steps);
+        self.viewed_step = position.checked_sub(1);
+        self.scroll_back = 0;
+    }
+
+    /// Scrolls the Output pane up by `delta` lines, or down when negative.
+    fn scroll_output(&mut self, delta: isize) {
+        let lines = match self.viewed_step {
+            Some(i) => self.steps.lock().unwrap().get(i).map_or(0, |step| step.log.len()),
+            None => self.output.lock().unwrap().len(),
+        };
+        self.scroll_back = self.scroll_back.saturating_add_signed(delta).min(lines);
+    }
+
+    fn resize_sidebar(&mut self, delta: i16) {
+        self.sidebar_width = self.sidebar_width.saturating_add_signed(delta).clamp(SIDEBAR_WIDTH.0, SIDEBAR_WIDTH.1);
+    }
+
+    /// Cycles the resolution of the conflict under the cursor; adopting is
+    /// only offered when the existing file could replace the repo's copy.
+    fn cycle_resolution(&mut self) {
+        let Some(conflict) = self.conflicts.get(self.conflict_index) else { return };
+        let current = self.resolutions.get(&conflict.target).copied().unwrap_or_default();
+        let next = match current {
+            Resolution::Skip => Resolution::Backup,
+            Resolution::Backup if conflict.adoptable => Resolution::Adopt,
+            Resolution::Backup | Resolution::Adopt => Resolution::Skip,
+        };
+        self.resolutions.insert(conflict.target.clone(), next);
+    }
+
+    /// Shows the Conflicts screen when a selected stow task would run into
+    /// existing files. Returns false when there is nothing to review.
+    fn review_conflicts(&mut self, tasks: &[Task]) -> bool {
+        self.conflicts.clear();
+        for task in tasks {
+            let TaskKind::Stow(stow) = &task.kind else { continue };
+            let Ok(plan) = stow::plan(stow) else { continue };
+            for conflict in plan.conflicts() {
+                self.resolutions.entry(conflict.target.clone()).or_insert(stow.on_conflict);
+                self.conflicts.push(conflict.clone());
+            }
+        }
+        if self.conflicts.is_empty() {
+            return false;
+        }
+        self.conflict_index = 0;
+        self.set_state(AppState::Conflicts);
+        true
+    }
+
     fn start_tasks(&mut self) {
-        let enabled_tasks = self.get_enabled_tasks();
+        let mut enabled_tasks = self.only.clone().unwrap_or_else(|| self.get_enabled_tasks());
         
         if enabled_tasks.is_empty() {
             return;
         }
 
-        if enabled_tasks.iter().any(|t| t.is_install) && self.sudo_password.is_empty() {
+        if !self.dry_run && !self.conflicts_reviewed && self.review_conflicts(&enabled_tasks) {
+            return;
+        }
+        for task in &mut enabled_tasks {
+            if let TaskKind::Stow(stow) = &mut task.kind {
+                stow.resolutions = self.resolutions.clone();
+            }
+        }
+
+        self.selection.record(&self.tasks);
+        self.selection.profile = self.profile.clone();
+        if let Err(err) = self.selection.save() {
+            self.output.lock().unwrap().push(format!("Could not save selection: {}", err));
+        }
+
+        let needs_sudo = enabled_tasks.iter().any(|t| t.kind.needs_sudo()) && !privilege::is_root();
+        if !self.dry_run && needs_sudo && self.sudo.is_none() {
             self.get_password();
             return;
         }
 
         self.set_state(AppState::Running);
+        self.resume = None;
+        self.confirm_cancel = false;
+        self.run_tasks = enabled_tasks.clone();
         
+        let deps = graph::dependencies(&enabled_tasks);
+        let depths = graph::depths(&deps);
         let mut steps = Vec::new();
-        for task in &enabled_tasks {
-            steps.push(Step { name: task.name.clone(), status: StepStatus::Pending });
+        for ((task, depends_on), depth) in enabled_tasks.iter().zip(deps).zip(depths) {
+            steps.push(Step {
+                name: task.name.clone(),
+                status: StepStatus::Pending,
+                error: Vec::new(),
+                depends_on,
+                depth,
+                log: Vec::new(),
+                started: None,
+                duration: None,
+            });
         }
         {
             let mut s = self.steps.lock().unwrap();
             *s = steps;
         }
         self.current_step.store(0, Ordering::Relaxed);
+        self.viewed_step = None;
+        self.scroll_back = 0;
+        self.run_started = Some(Instant::now());
         
         {
             let mut out = self.output.lock().unwrap();
             out.clear();
-            out.push("Starting tasks...".to_string());
+            out.push(if self.dry_run { "Planning tasks (dry run)..." } else { "Starting tasks..." }.to_string());
         }
 
-        let password = self.sudo_password.clone();
-        let config = self.config.clone();
+        let options = RunOptions { dry_run: self.dry_run, jobs: self.jobs, on_failure: self.on_failure };
+        let sudo = self.sudo.clone();
         let tasks: Vec<Task> = enabled_tasks;
+        let mut reporter: Arc<dyn Reporter> = Arc::new(TuiReporter {
+            ids: (self.jobs > 1).then(|| tasks.iter().map(|t| t.id.clone()).collect()),
+            output: Arc::clone(&self.output),
+            steps: Arc::clone(&self.steps),
+            current_step: Arc::clone(&self.current_step),
+        });
+        let mut log = None;
+        if !self.dry_run {
+            let resolved = Resolved {
+                source: self.config_source.to_string(),
+                profile: self.profile.clone(),
+                jobs: self.jobs,
+                tasks: &tasks,
+            };
+            match RunLog::create(&resolved, Arc::clone(&reporter)) {
+                Ok(run_log) => {
+                    reporter = Arc::clone(&run_log) as Arc<dyn Reporter>;
+                    log = Some(run_log);
+                }
+                Err(err) => self.output.lock().unwrap().push(format!("Could not create a run log: {:#}", err)),
+            }
+        }
         let output = Arc::clone(&self.output);
-        let steps = Arc::clone(&self.steps);
-        let current_step = Arc::clone(&self.current_step);
         let running = Arc::clone(&self.running);
         let state = Arc::clone(&self.state);
 
         thread::spawn(move || {
-            for (i, task) in tasks.iter().enumerate() {
-                if !running.load(Ordering::Relaxed) {
-                    let mut out = output.lock().unwrap();
-                    out.push("\n=== Tasks cancelled ===".to_string());
-                    break;
-                }
-
-                {
-                    let mut s = steps.lock().unwrap();
-                    if i < s.len() {
-                        s[i].status = StepStatus::Running;
-                    }
-                }
-                current_step.store(i, Ordering::Relaxed);
-
-                let result = match task.id.as_str() {
-                    "repos" => run_repos(&config, &password),
-                    "dnf" => run_dnf(&config, &password),
-                    "docker" => run_docker(&config, &password),
-                    "flatpak" => run_flatpak(&config),
-                    "homebrew" => run_homebrew(&config),
-                    "opencode" => run_opencode(&config, &password),
-                    "cargo" => run_cargo(&config),
-                    "terra" => run_terra(&config, &password),
-                    "stow" => run_stow_task(),
-                    _ => Ok(()),
-                };
-
-                {
-                    let mut s = steps.lock().unwrap();
-                    if i < s.len() {
-                        s[i].status = if result.is_ok() { StepStatus::Completed } else { StepStatus::Failed };
-                    }
-                }
+            let statuses = engine::run_tasks(&tasks, sudo, &options, &running, reporter);
 
-                {
-                    let mut out = output.lock().unwrap();
-                    if result.is_ok() {
-                        out.push(format!("✓ {} completed", task.name));
-                    } else {
-                        out.push(format!("✗ {} failed", task.name));
-                    }
-                }
+            let mut out = output.lock().unwrap();
+            if let Some(log) = log {
+                log.finish(&statuses);
+                out.push(format!("Log: {} (`dot-setup log {}`)", log.dir().display(), log.id()));
             }
-
             if running.load(Ordering::Relaxed) {
-                let mut out = output.lock().unwrap();
                 out.push("\n=== All tasks complete! ===".to_string());
                 out.push("Press ESC to return".to_string());
+            } else {
+                out.push("\n=== Tasks cancelled ===".to_string());
             }
-            
+            drop(out);
+
             state.store(AppState::Done.as_usize(), Ordering::Relaxed);
         });
     }
 }
 
-fn run_repos(config: &Config, password: &str) -> Result<()> {
-    let repo = &config.repositories;
-    let cmd = format!(
-        "echo '{}' | sudo -S dnf install -y {} {}",
-        password, repo.rpm_fusion_free, repo.rpm_fusion_nonfree
-    );
-    execute_cmd(&cmd)?;
-    
-    let cmd = format!(
-        "echo '{}' | sudo -S dnf config-manager addrepo --overwrite --from-repofile {}",
-        password, repo.docker
-    );
-    execute_cmd(&cmd)?;
-    
-    let cmd = format!(
-        "echo '{}' | sudo -S bash -c 'if ! rpm -q terra-release &>/dev/null; then dnf install --nogpgcheck --repofrompath terra,{} terra-release; fi'",
-        password, repo.terra
-    );
-    execute_cmd(&cmd)?;
-    
-    Ok(())
-}
-
-fn run_dnf(config: &Config, password: &str) -> Result<()> {
-    let cmd = config.commands.update.clone();
-    let full_cmd = format!("echo '{}' | sudo -S {}", password, cmd);
-    execute_cmd(&full_cmd)?;
-    
-    let pkg = &config.packages.dnf;
-    let cmd = format!("echo '{}' | sudo -S dnf install -y {}", password, pkg.packages.join(" "));
-    execute_cmd(&cmd)?;
-    
-    Ok(())
+/// Feeds engine progress into the stepper and Output pane.
+struct TuiReporter {
+    /// Set when tasks run in parallel, so interleaved output lines can be
+    /// prefixed with the id of the task that printed them.
+    ids: Option<Vec<String>>,
+    output: Arc<Mutex<Vec<String>>>,
+    steps: Arc<Mutex<Vec<Step>>>,
+    current_step: Arc<AtomicUsize>,
 }
 
-fn run_docker(config: &Config, password: &str) -> Result<()> {
-    let pkg = &config.packages.docker;
-    let cmd = format!("echo '{}' | sudo -S dnf install -y {}", password, pkg.packages.join(" "));
-    execute_cmd(&cmd)?;
-    
-    if pkg.enable_service {
-        let cmd = format!("echo '{}' | sudo -S systemctl enable --now docker", password);
-        execute_cmd(&cmd)?;
+impl Reporter for TuiReporter {
+    fn task_started(&self, index: usize, task: &Task) {
+        if let Some(step) = self.steps.lock().unwrap().get_mut(index) {
+            step.status = StepStatus::Running;
+            step.started = Some(Instant::now());
+        }
+        self.current_step.store(index, Ordering::Relaxed);
+        self.output.lock().unwrap().push(format!("=== {} ===", task.name));
     }
-    
-    Ok(())
-}
-
-fn run_flatpak(config: &Config) -> Result<()> {
-    let pkg = &config.packages.flatpak;
-    let cmd = format!(
-        "flatpak remote-add --if-not-exists {} https://dl.flathub.org/repo/flathub.flatpakrepo",
-        pkg.remote
-    );
-    execute_cmd(&cmd)?;
-    
-    let cmd = format!("flatpak install -y {}", pkg.apps.join(" "));
-    execute_cmd(&cmd)?;
-    
-    Ok(())
-}
-
-fn run_homebrew(config: &Config) -> Result<()> {
-    let pkg = &config.packages.homebrew;
-    let cmd = format!("/bin/bash -c \"$(curl -fsSL {})\"", pkg.install_script);
-    execute_cmd(&cmd)?;
-    
-    let cmd = format!(
-        "eval \"$( {})\" && brew install {}",
-        config.commands.shell_init,
-        pkg.packages.join(" ")
-    );
-    execute_cmd(&cmd)?;
-    
-    Ok(())
-}
-
-fn run_opencode(config: &Config, password: &str) -> Result<()> {
-    let pkg = &config.packages.opencode;
-    let cmd = format!("echo '{}' | sudo -S dnf install -y {}", password, pkg.url);
-    execute_cmd(&cmd)?;
-    
-    Ok(())
-}
-
-fn run_cargo(config: &Config) -> Result<()> {
-    let pkg = &config.packages.cargo;
-    let cmd = format!("cargo install {}", pkg.packages.join(" "));
-    execute_cmd(&cmd)?;
-    
-    Ok(())
-}
 
-fn run_terra(config: &Config, password: &str) -> Result<()> {
-    let pkg = &config.packages.terra;
-    let cmd = format!("echo '{}' | sudo -S dnf install -y {}", password, pkg.packages.join(" "));
-    execute_cmd(&cmd)?;
-    
-    Ok(())
-}
-
-fn run_stow_task() -> Result<()> {
-    execute_cmd("mkdir -p $HOME/.local/bin && stow -R -t $HOME/ --dotfiles .")?;
-    Ok(())
-}
-
-fn execute_cmd(cmd: &str) -> Result<()> {
-    let output = Command::new("sh")
-        .args(["-c", cmd])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()?;
-
-    if !output.status.success() {
-        return Err(color_eyre::eyre::anyhow!("Command failed"));
+    fn output(&self, index: usize, line: String) {
+        if let Some(step) = self.steps.lock().unwrap().get_mut(index) {
+            step.log.push(line.clone());
+        }
+        let line = match &self.ids {
+            Some(ids) => format!("[{}] {}", ids[index], line),
+            None => line,
+        };
+        self.output.lock().unwrap().push(line);
     }
-    Ok(())
-}
 
-fn load_config() -> Result<Config> {
-    let config_path = get_config_path()?;
-    let content = fs::read_to_string(&config_path)?;
-    let config: Config = toml::from_str(&content)?;
-    Ok(config)
+    fn task_finished(&self, index: usize, task: &Task, status: StepStatus, error: &[String]) {
+        let lines = engine::status_lines(task, status, error);
+        if let Some(step) = self.steps.lock().unwrap().get_mut(index) {
+            step.status = status;
+            step.error = error.to_vec();
+            step.duration = step.started.map(|started| started.elapsed());
+            step.log.extend(lines.iter().cloned());
+        }
+        self.output.lock().unwrap().extend(lines);
+    }
 }
 
-fn get_config_path() -> Result<PathBuf> {
-    let exe_path = std::env::current_exe()?;
-    let config_path = exe_path.with_file_name("config.toml");
-    
-    if config_path.exists() {
-        return Ok(config_path);
-    }
-    
-    let fallback = PathBuf::from("config.toml");
-    if fallback.exists() {
-        return Ok(fallback);
+fn main() -> Result<ExitCode> {
+    let cli = Cli::parse();
+    let load_options = cli.load_options();
+    match cli.command {
+        Some(command) => return Ok(cli::run(command, &load_options)),
+        None if cli.dry_run => return Ok(cli::run(Commands::Plan(Default::default()), &load_options)),
+        None => {}
     }
-    
-    Ok(config_path)
-}
 
-fn main() -> Result<()> {
     enable_raw_mode()?;
     let mut stdout = io::stdout();
     execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    let mut app = App::new();
-    let res = run_app(&mut terminal, &mut app);
+    let (res, code) = match App::new(load_options) {
+        Ok(mut app) => (run_app(&mut terminal, &mut app), ExitCode::SUCCESS),
+        // Bad arguments or config, like the headless commands.
+        Err(err) => (show_error(&mut terminal, &err), ExitCode::from(2)),
+    };
 
     disable_raw_mode()?;
     execute!(
@@ -464,11 +637,63 @@ fn main() -> Result<()> {
         eprintln!("Error: {:?}", err);
     }
 
-    Ok(())
+    Ok(code)
+}
+
+/// Shows why the config could not be loaded until a key is pressed.
+fn show_error(terminal: &mut Terminal<CrosstermBackend<io::Stdout>>, err: &color_eyre::Report) -> Result<()> {
+    let mut lines = vec![Line::styled("Could not load the configuration:", Style::default().fg(Color::Red)), Line::raw("")];
+    match err.downcast_ref::<check::Invalid>() {
+        Some(invalid) => {
+            for problem in &invalid.problems {
+                let color = if problem.is_error() { Color::Red } else { Color::Yellow };
+                // Message, `-->` location, gutter, source line, underline.
+                let styles = [color, Color::Cyan, Color::DarkGray, Color::White, color];
+                for (line, color) in problem.to_string().lines().zip(styles) {
+                    lines.push(Line::styled(line.to_string(), Style::default().fg(color)));
+                }
+                lines.push(Line::raw(""));
+            }
+        }
+        None => {
+            for cause in err.chain() {
+                lines.extend(cause.to_string().lines().map(|line| Line::raw(line.to_string())));
+            }
+        }
+    }
+
+    let mut scroll: u16 = 0;
+    loop {
+        terminal.draw(|frame| {
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Min(0), Constraint::Length(3)])
+                .split(frame.area());
+            let error = Paragraph::new(lines.clone())
+                .scroll((scroll, 0))
+                .block(Block::default().borders(Borders::ALL).title("Error"));
+            frame.render_widget(error, chunks[0]);
+            let help = Paragraph::new("↑↓ Scroll | Any other key to exit")
+                .style(Style::default().fg(Color::DarkGray))
+                .block(Block::default().borders(Borders::ALL));
+            frame.render_widget(help, chunks[1]);
+        })?;
+        if let Event::Key(key) = event::read()? {
+            match key.code {
+                _ if key.kind != KeyEventKind::Press => {}
+                KeyCode::Up => scroll = scroll.saturating_sub(1),
+                KeyCode::Down if usize::from(scroll) + 1 < lines.len() => scroll += 1,
+                KeyCode::Down => {}
+                _ => return Ok(()),
+            }
+        }
+    }
 }
 
 fn run_app(terminal: &mut Terminal<CrosstermBackend<io::Stdout>>, app: &mut App) -> Result<()> {
-    let mut password_input = String::new();
+    // Reserved up front so typing never reallocates and leaves stray copies
+    // of the password behind.
+    let mut password_input = Zeroizing::new(String::with_capacity(256));
 
     loop {
         let state = app.get_state();
@@ -482,16 +707,16 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<io::Stdout>>, app: &mut App)
                         AppState::GettingPassword => {
                             match key.code {
                                 KeyCode::Enter => {
-                                    app.sudo_password = password_input.clone();
+                                    let password = Zeroizing::new(password_input.to_string());
                                     password_input.clear();
-                                    app.running.store(true, Ordering::Relaxed);
-                                    app.start_tasks();
+                                    app.submit_password(password);
                                 }
                                 KeyCode::Backspace => {
                                     password_input.pop();
                                 }
                                 KeyCode::Esc => {
                                     app.set_state(AppState::Selection);
+                                    app.password_error = None;
                                     password_input.clear();
                                 }
                                 KeyCode::Char(c) => {
@@ -500,33 +725,79 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<io::Stdout>>, app: &mut App)
                                 _ => {}
                             }
                         }
-                        AppState::Selection => {
+                        AppState::Conflicts => {
                             match key.code {
-                                KeyCode::Up => {
-                                    if app.selected_index > 0 {
-                                        app.selected_index -= 1;
-                                    }
+                                KeyCode::Up if app.conflict_index > 0 => {
+                                    app.conflict_index -= 1;
                                 }
-                                KeyCode::Down => {
-                                    if app.selected_index < app.tasks.len() {
-                                        app.selected_index += 1;
-                                    }
-                                }
-                                KeyCode::Char(' ') => {
-                                    if app.selected_index < app.tasks.len() {
-                                        app.toggle_task();
-                                    } else if app.selected_index == app.tasks.len() {
-                                        app.running.store(true, Ordering::Relaxed);
-                                        app.start_tasks();
-                                    }
+                                KeyCode::Down if app.conflict_index + 1 < app.conflicts.len() => {
+                                    app.conflict_index += 1;
+                                }
+                                KeyCode::Char(' ') | KeyCode::Left | KeyCode::Right => {
+                                    app.cycle_resolution();
                                 }
                                 KeyCode::Enter => {
-                                    if app.selected_index < app.tasks.len() {
-                                        app.toggle_task();
-                                    } else if app.selected_index == app.tasks.len() {
-                                        app.running.store(true, Ordering::Relaxed);
-                                        app.start_tasks();
-                                    }
+                                    app.conflicts_reviewed = true;
+                                    app.start_tasks();
+                                }
+                                KeyCode::Esc => {
+                                    app.set_state(AppState::Selection);
+                                }
+                                _ => {}
+                            }
+                        }
+                        AppState::Selection if app.filtering => {
+                            match key.code {
+                                KeyCode::Enter => {
+                                    app.filtering = false;
+                                }
+                                KeyCode::Esc => {
+                                    app.filtering = false;
+                                    app.set_filter(String::new());
+                                }
+                                KeyCode::Backspace => {
+                                    let mut filter = app.filter.clone();
+                                    filter.pop();
+                                    app.set_filter(filter);
+                                }
+                                KeyCode::Char(c) => {
+                                    let filter = format!("{}{}", app.filter, c);
+                                    app.set_filter(filter);
+                                }
+                                _ => {}
+                            }
+                        }
+                        AppState::Selection => {
+                            match key.code {
+                                KeyCode::Up if app.selected_index > 0 => {
+                                    app.selected_index -= 1;
+                                }
+                                KeyCode::Down if app.selected_index + 1 < app.rows().len() => {
+                                    app.selected_index += 1;
+                                }
+                                KeyCode::Char(' ') | KeyCode::Enter => {
+                                    app.activate();
+                                }
+                                KeyCode::Right | KeyCode::Char('l') => {
+                                    app.set_expanded(true);
+                                }
+                                KeyCode::Left | KeyCode::Char('h') => {
+                                    app.set_expanded(false);
+                                }
+                                KeyCode::Char('/') => {
+                                    app.filtering = true;
+                                }
+                                KeyCode::Esc if !app.filter.is_empty() => {
+                                    app.set_filter(String::new());
+                                }
+                                KeyCode::Char('d') => {
+                                    app.dry_run = !app.dry_run;
+                                }
+                                KeyCode::Char('p') => {
+                                    app.cycle_profile();
+                                }
+                                KeyCode::Char('r') => {
+                                    app.resume();
                                 }
                                 KeyCode::Esc => {
                                     return Ok(());
@@ -534,16 +805,54 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<io::Stdout>>, app: &mut App)
                                 _ => {}
                             }
                         }
+                        AppState::Running if app.confirm_cancel => {
+                            match key.code {
+                                KeyCode::Char('y') => {
+                                    app.cancel();
+                                }
+                                KeyCode::Char('n') | KeyCode::Esc => {
+                                    app.confirm_cancel = false;
+                                }
+                                _ => {}
+                            }
+                        }
                         AppState::Running => {
-                            if key.code == KeyCode::Esc {
-                                app.running.store(false, Ordering::Relaxed);
+                            match key.code {
+                                KeyCode::Esc => {
+                                    app.request_cancel();
+                                }
+                                KeyCode::Up => {
+                                    app.view_step(-1);
+                                }
+                                KeyCode::Down => {
+                                    app.view_step(1);
+                                }
+                                KeyCode::PageUp => {
+                                    app.scroll_output(10);
+                                }
+                                KeyCode::PageDown => {
+                                    app.scroll_output(-10);
+                                }
+                                KeyCode::End => {
+                                    app.scroll_back = 0;
+                                }
+                                KeyCode::Char('<') => {
+                                    app.resize_sidebar(-4);
+                                }
+                                KeyCode::Char('>') => {
+                                    app.resize_sidebar(4);
+                                }
+                                _ => {}
                             }
                         }
                         AppState::Done => {
-                            if key.code == KeyCode::Esc {
+                            if key.code == KeyCode::Char('r') {
+                                app.retry();
+                            } else if key.code == KeyCode::Esc {
                                 app.set_state(AppState::Selection);
                                 app.selected_index = 0;
-                                app.sudo_password.clear();
+                                app.sudo = None;
+                                app.only = None;
                                 let mut out = app.output.lock().unwrap();
                                 out.clear();
                                 out.push("Welcome to dot-setup".to_string());
@@ -553,12 +862,6 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<io::Stdout>>, app: &mut App)
                 }
             }
         }
-        
-        let current_state = app.get_state();
-        if current_state == AppState::Running {
-            let out = app.output.lock().unwrap();
-            app.scroll = (out.len() as u16).saturating_sub(1);
-        }
     }
 }
 
@@ -572,7 +875,15 @@ fn ui(frame: &mut Frame, app: &App) {
         ])
         .split(frame.area());
 
-    let title = Paragraph::new("dot-setup")
+    let mut title = vec![Span::raw(format!("dot-setup — {}", app.distro))];
+    if let Some(profile) = &app.profile {
+        title.push(Span::raw(format!(" — profile: {}", profile)));
+    }
+    title.push(Span::raw(format!(" — config: {}", app.config_source.describe())));
+    if let Some(err) = &app.profile_error {
+        title.push(Span::styled(format!(" — {}", err), Style::default().fg(Color::Red)));
+    }
+    let title = Paragraph::new(Line::from(title))
         .style(Style::default().fg(Color::Cyan))
         .block(Block::default().borders(Borders::ALL).title("Setup"));
     frame.render_widget(title, chunks[0]);
@@ -581,82 +892,322 @@ fn ui(frame: &mut Frame, app: &App) {
 
     match state {
         AppState::GettingPassword => {
-            let prompt = Paragraph::new("Enter sudo password:")
+            let mut prompt = vec![Line::from("Enter sudo password:")];
+            if let Some(err) = &app.password_error {
+                prompt.push(Line::styled(err.as_str(), Style::default().fg(Color::Red)));
+            }
+            let prompt = Paragraph::new(prompt)
                 .style(Style::default().fg(Color::Yellow))
                 .block(Block::default().borders(Borders::ALL).title("Password"));
             frame.render_widget(prompt, chunks[1]);
         }
-        AppState::Selection => {
-            let mut items: Vec<ListItem> = app
-                .tasks
+        AppState::Conflicts => {
+            let items: Vec<ListItem> = app
+                .conflicts
                 .iter()
                 .enumerate()
-                .map(|(i, task)| {
-                    let checkbox = if task.enabled { "[x]" } else { "[ ]" };
-                    let content = format!("{} {}", checkbox, task.name);
-                    let style = if i == app.selected_index {
-                        Style::default().fg(Color::Yellow).add_modifier(ratatui::style::Modifier::BOLD)
+                .map(|(i, conflict)| {
+                    let resolution = app.resolutions.get(&conflict.target).copied().unwrap_or_default();
+                    let color = match resolution {
+                        Resolution::Skip => Color::DarkGray,
+                        Resolution::Backup => Color::Yellow,
+                        Resolution::Adopt => Color::Magenta,
+                    };
+                    let style = if i == app.conflict_index {
+                        Style::default().add_modifier(ratatui::style::Modifier::BOLD)
                     } else {
-                        Style::default().fg(Color::White)
+                        Style::default()
                     };
-                    ListItem::new(Span::styled(content, style))
+                    ListItem::new(Line::from(vec![
+                        Span::styled(format!("[{:<6}]", resolution.name()), style.fg(color)),
+                        Span::styled(format!(" {} ", conflict.target.display()), style.fg(Color::White)),
+                        Span::styled(conflict.reason.as_str(), Style::default().fg(Color::DarkGray)),
+                    ]))
                 })
                 .collect();
 
-            let confirm_idx = app.tasks.len();
-            let confirm_style = if app.selected_index == confirm_idx {
-                Style::default().fg(Color::Green).add_modifier(ratatui::style::Modifier::BOLD)
+            let list = List::new(items).block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(format!("{} files are in the way of the stow links", app.conflicts.len())),
+            );
+            let mut list_state = ListState::default().with_selected(Some(app.conflict_index));
+            frame.render_stateful_widget(list, chunks[1], &mut list_state);
+        }
+        AppState::Selection => {
+            let highlight = Style::default().fg(Color::Yellow).add_modifier(ratatui::style::Modifier::BOLD);
+            let items: Vec<ListItem> = app
+                .rows()
+                .into_iter()
+                .enumerate()
+                .map(|(i, row)| {
+                    let selected = i == app.selected_index;
+                    match row {
+                        Row::Task(t) => {
+                            let task = &app.tasks[t];
+                            let items = task.kind.items();
+                            let checkbox = if task.enabled { "[x]" } else { "[ ]" };
+                            let content = if items.is_empty() {
+                                format!("  {} {}", checkbox, task.name)
+                            } else {
+                                let arrow = if app.expanded.contains(&task.id) || !app.filter.is_empty() { "▾" } else { "▸" };
+                                let chosen = items.iter().filter(|item| !app.selection.is_excluded(task, item)).count();
+                                format!("{} {} {} ({}/{})", arrow, checkbox, task.name, chosen, items.len())
+                            };
+                            let style = if selected { highlight } else { Style::default().fg(Color::White) };
+                            ListItem::new(Span::styled(content, style))
+                        }
+                        Row::Item(t, item) => {
+                            let task = &app.tasks[t];
+                            let checkbox = if app.selection.is_excluded(task, &item) { "[ ]" } else { "[x]" };
+                            let style = if selected {
+                                highlight
+                            } else if task.enabled {
+                                Style::default().fg(Color::White)
+                            } else {
+                                Style::default().fg(Color::DarkGray)
+                            };
+                            ListItem::new(Span::styled(format!("      {} {}", checkbox, item), style))
+                        }
+                        Row::Confirm => {
+                            let style = if selected {
+                                Style::default().fg(Color::Green).add_modifier(ratatui::style::Modifier::BOLD)
+                            } else {
+                                Style::default().fg(Color::DarkGray)
+                            };
+                            ListItem::new(Span::styled(">>> Confirm <<<", style))
+                        }
+                    }
+                })
+                .collect();
+
+            let mut area = chunks[1];
+            let resume = app.resume_tasks();
+            if let (Some(run), Some(first)) = (&app.resume, resume.first()) {
+                let parts = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([Constraint::Length(3), Constraint::Min(0)])
+                    .split(area);
+                let ended = match run.outcome {
+                    Some(runlog::Outcome::Failed) => "failed",
+                    Some(runlog::Outcome::Cancelled) => "was cancelled",
+                    _ => "did not finish",
+                };
+                let banner = Paragraph::new(format!(
+                    "The last run {} at {}. Press r to resume from there ({} of {} steps left).",
+                    ended,
+                    first.name,
+                    resume.len(),
+                    run.steps.len()
+                ))
+                .style(Style::default().fg(Color::Yellow))
+                .block(Block::default().borders(Borders::ALL).title(format!("Resume run {}", run.id)));
+                frame.render_widget(banner, parts[0]);
+                area = parts[1];
+            }
+
+            let area = if app.filtering || !app.filter.is_empty() {
+                let parts = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([Constraint::Length(3), Constraint::Min(0)])
+                    .split(area);
+                let cursor = if app.filtering { "_" } else { "" };
+                let filter = Paragraph::new(format!("{}{}", app.filter, cursor))
+                    .style(Style::default().fg(if app.filtering { Color::Yellow } else { Color::White }))
+                    .block(Block::default().borders(Borders::ALL).title("Filter"));
+                frame.render_widget(filter, parts[0]);
+                parts[1]
             } else {
-                Style::default().fg(Color::DarkGray)
+                area
             };
-            items.push(ListItem::new(Span::styled(">>> Confirm <<<", confirm_style)));
 
+            let title = if app.dry_run {
+                "Select tasks to plan (Space to toggle) [DRY RUN]"
+            } else {
+                "Select tasks to run (Space to toggle)"
+            };
             let list = List::new(items)
-                .block(Block::default().borders(Borders::ALL).title("Select tasks to run (Space to toggle)"))
+                .block(Block::default().borders(Borders::ALL).title(title))
                 .style(Style::default().fg(Color::White));
 
-            frame.render_widget(list, chunks[1]);
+            let mut list_state = ListState::default().with_selected(Some(app.selected_index));
+            frame.render_stateful_widget(list, area, &mut list_state);
         }
-        AppState::Running | AppState::Done => {
+        AppState::Done => {
             let steps = app.steps.lock().unwrap();
-            let current = app.current_step.load(Ordering::Relaxed) as usize;
-            
-            let stepper: Vec<Line> = steps
+            let failed = steps.iter().filter(|s| s.status == StepStatus::Failed).count();
+            let completed = steps.iter().filter(|s| s.status == StepStatus::Completed).count();
+            let already_done = steps.iter().filter(|s| s.status == StepStatus::AlreadyDone).count();
+            let cancelled = steps.iter().filter(|s| s.status == StepStatus::Cancelled).count();
+            let skipped = steps.iter().filter(|s| s.status == StepStatus::Skipped).count();
+
+            let mut summary = vec![Line::from(format!(
+                "{} completed, {} already done, {} failed, {} skipped, {} cancelled, {} not run",
+                completed,
+                already_done,
+                failed,
+                skipped,
+                cancelled,
+                steps.len() - completed - already_done - failed - skipped - cancelled
+            ))];
+            for step in steps.iter() {
+                let (icon, color) = match step.status {
+                    StepStatus::Completed => ("●", Color::Green),
+                    StepStatus::AlreadyDone => ("✓", Color::Green),
+                    StepStatus::Failed => ("✗", Color::Red),
+                    StepStatus::Cancelled => ("⊘", Color::Magenta),
+                    StepStatus::Skipped => ("↷", Color::DarkGray),
+                    _ => ("○", Color::DarkGray),
+                };
+                let elapsed = step.duration.map(|d| format!(" {}", Elapsed(d.as_secs()))).unwrap_or_default();
+                summary.push(Line::from(vec![
+                    Span::styled(icon, Style::default().fg(color)),
+                    Span::raw(format!(" {}", step.name)),
+                    Span::styled(elapsed, Style::default().fg(Color::Cyan)),
+                ]));
+                if step.status == StepStatus::Skipped {
+                    let reason = format!("    skipped: {}", step.error.join("; "));
+                    summary.push(Line::styled(reason, Style::default().fg(Color::DarkGray)));
+                    continue;
+                }
+                for line in &step.error {
+                    summary.push(Line::styled(format!("    {}", line), Style::default().fg(Color::Red)));
+                }
+            }
+
+            let summary_widget = Paragraph::new(summary)
+                .block(Block::default().borders(Borders::ALL).title("Summary"));
+            frame.render_widget(summary_widget, chunks[1]);
+        }
+        AppState::Running => {
+            let steps = app.steps.lock().unwrap();
+            let current = app.current_step.load(Ordering::Relaxed);
+
+            let rows = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Length(3), Constraint::Min(0)])
+                .split(chunks[1]);
+            let finished = steps
                 .iter()
-                .enumerate()
-                .map(|(i, step)| {
-                    let (icon, color) = match step.status {
-                        StepStatus::Pending => ("○", Color::DarkGray),
-                        StepStatus::Running => ("◐", Color::Yellow),
-                        StepStatus::Completed => ("●", Color::Green),
-                        StepStatus::Failed => ("✗", Color::Red),
-                    };
-                    let prefix = if i == current { "> " } else { "  " };
-                    let style = Style::default().fg(color);
-                    Line::from(vec![Span::raw(prefix), Span::styled(icon, style), Span::raw(format!(" {}", step.name))])
-                })
-                .collect();
-            
-            let stepper_widget = Paragraph::new(stepper)
-                .block(Block::default().borders(Borders::ALL).title("Progress"));
-            frame.render_widget(stepper_widget, chunks[1]);
+                .filter(|step| !matches!(step.status, StepStatus::Pending | StepStatus::Running))
+                .count();
+            let elapsed = app.run_started.map_or(0, |started| started.elapsed().as_secs());
+            let gauge = Gauge::default()
+                .block(Block::default().borders(Borders::ALL).title("Overall"))
+                .gauge_style(Style::default().fg(Color::Green))
+                .ratio(finished as f64 / steps.len().max(1) as f64)
+                .label(format!("{}/{} steps, {}", finished, steps.len(), Elapsed(elapsed)));
+            frame.render_widget(gauge, rows[0]);
+
+            let sidebar = app.sidebar_width.min(rows[1].width.saturating_sub(SIDEBAR_WIDTH.0));
+            let panes = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Length(sidebar), Constraint::Min(0)])
+                .split(rows[1]);
+
+            let mut stepper = vec![ListItem::new(Line::from(vec![
+                Span::raw("  "),
+                Span::styled("≡", Style::default().fg(Color::Cyan)),
+                Span::raw(" All steps"),
+            ]))];
+            stepper.extend(steps.iter().enumerate().map(|(i, step)| {
+                let waiting_for: Vec<&str> = step
+                    .depends_on
+                    .iter()
+                    .filter(|&&dep| matches!(steps[dep].status, StepStatus::Pending | StepStatus::Running))
+                    .map(|&dep| steps[dep].name.as_str())
+                    .collect();
+                let (icon, color) = match step.status {
+                    StepStatus::Pending => ("○", Color::DarkGray),
+                    StepStatus::Running => ("◐", Color::Yellow),
+                    StepStatus::Completed => ("●", Color::Green),
+                    StepStatus::AlreadyDone => ("✓", Color::Green),
+                    StepStatus::Failed => ("✗", Color::Red),
+                    StepStatus::Cancelled => ("⊘", Color::Magenta),
+                    StepStatus::Skipped => ("↷", Color::DarkGray),
+                };
+                let prefix = if i == current || step.status == StepStatus::Running { "> " } else { "  " };
+                let indent = if step.depth > 0 { format!("{}└ ", "  ".repeat(step.depth - 1)) } else { String::new() };
+                let style = Style::default().fg(color);
+                let name_style = if step.status == StepStatus::Skipped {
+                    Style::default().fg(Color::DarkGray).add_modifier(ratatui::style::Modifier::CROSSED_OUT)
+                } else {
+                    Style::default()
+                };
+                let elapsed = step.elapsed().map(|d| format!(" {}", Elapsed(d.as_secs()))).unwrap_or_default();
+                let suffix = match step.status {
+                    StepStatus::AlreadyDone => " (already done)".to_string(),
+                    StepStatus::Skipped => format!(" (skipped: {})", step.error.join("; ")),
+                    StepStatus::Pending if !waiting_for.is_empty() => format!(" (waiting for {})", waiting_for.join(", ")),
+                    _ => String::new(),
+                };
+                ListItem::new(Line::from(vec![
+                    Span::raw(prefix),
+                    Span::raw(indent),
+                    Span::styled(icon, style),
+                    Span::styled(format!(" {}", step.name), name_style),
+                    Span::styled(elapsed, Style::default().fg(Color::Cyan)),
+                    Span::styled(suffix, Style::default().fg(Color::DarkGray)),
+                ]))
+            }));
+
+            let stepper_widget = List::new(stepper)
+                .block(Block::default().borders(Borders::ALL).title("Progress"))
+                .highlight_style(Style::default().add_modifier(ratatui::style::Modifier::REVERSED));
+            let mut stepper_state = ListState::default().with_selected(Some(app.viewed_step.map_or(0, |i| i + 1)));
+            frame.render_stateful_widget(stepper_widget, panes[0], &mut stepper_state);
 
             let output = app.output.lock().unwrap();
-            let output_text: Vec<Line> = output
+            let (lines, title) = match app.viewed_step.and_then(|i| steps.get(i)) {
+                Some(step) => (&step.log, format!("Output — {}", step.name)),
+                None => (&*output, "Output — all steps".to_string()),
+            };
+            let height = usize::from(panes[1].height.saturating_sub(2));
+            let bottom = lines.len().saturating_sub(height);
+            let top = bottom.saturating_sub(app.scroll_back);
+            let title = if app.scroll_back > 0 {
+                format!("{} (scrolled up, End to follow)", title)
+            } else {
+                title
+            };
+            let output_text: Vec<Line> = lines[top..]
                 .iter()
+                .take(height)
                 .map(|s| Line::from(s.as_str()))
                 .collect();
             let output_widget = Paragraph::new(output_text)
-                .block(Block::default().borders(Borders::ALL).title("Output"))
-                .scroll((app.scroll, 0));
-            frame.render_widget(output_widget, chunks[1]);
+                .block(Block::default().borders(Borders::ALL).title(title));
+            frame.render_widget(output_widget, panes[1]);
+            drop(output);
+            drop(steps);
+
+            if app.confirm_cancel {
+                let area = centered(frame.area(), 64, 9);
+                let prompt = Paragraph::new(vec![
+                    Line::from(format!("{} is still running.", app.running_transactions().join(", "))),
+                    Line::from("Killing dnf or apt mid-transaction can leave the package database locked or half-updated."),
+                    Line::from(""),
+                    Line::from("y Cancel anyway | n Keep running"),
+                ])
+                .wrap(Wrap { trim: false })
+                .style(Style::default().fg(Color::Yellow))
+                .block(Block::default().borders(Borders::ALL).title("Cancel the run?"));
+                frame.render_widget(Clear, area);
+                frame.render_widget(prompt, area);
+            }
         }
     }
 
     let help_text = match state {
         AppState::GettingPassword => "Type password | Enter Submit | Esc Cancel",
-        AppState::Selection => "↑↓ Select | Space/Enter Toggle | Esc Exit",
-        AppState::Running => "Press ESC to cancel...",
+        AppState::Conflicts => "↑↓ Select | Space/←→ Skip, back up or adopt | Enter Continue | Esc Back",
+        AppState::Selection if app.filtering => "Type to filter | Enter Keep | Esc Clear",
+        AppState::Selection => "↑↓ Select | Space/Enter Toggle | →← Expand/Collapse | / Filter | p Profile | d Dry run | Esc Exit",
+        AppState::Running if app.confirm_cancel => "y Cancel anyway | n/Esc Keep running",
+        AppState::Running => "↑↓ Step log | PgUp/PgDn Scroll | End Follow | < > Resize | Esc Cancel",
+        AppState::Done if !app.run_tasks.is_empty() && app.steps.lock().unwrap().iter().any(|s| !s.status.is_done()) => {
+            "r Retry failed steps | Esc Return"
+        }
         AppState::Done => "Press ESC to return",
     };
     let help = Paragraph::new(help_text)
@@ -664,3 +1215,10 @@ fn ui(frame: &mut Frame, app: &App) {
         .block(Block::default().borders(Borders::ALL));
     frame.render_widget(help, chunks[2]);
 }
+
+/// A `width` x `height` rectangle in the middle of `area`.
+fn centered(area: Rect, width: u16, height: u16) -> Rect {
+    let width = width.min(area.width);
+    let height = height.min(area.height);
+    Rect::new(area.x + (area.width - width) / 2, area.y + (area.height - height) / 2, width, height)
+}
@@ -0,0 +1,143 @@
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+/// How often the sudo timestamp is refreshed while tasks are running. sudo's
+/// default `timestamp_timeout` is 5 minutes.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Whether dot-setup itself runs as root, in which case privileged
+/// commands don't go through sudo at all.
+pub fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// A validated sudo credential.
+///
+/// The password is only ever written to the stdin of `sudo -S -v`, never put
+/// on a command line. Privileged commands themselves run through `sudo -n`
+/// against the cached timestamp, so the password can't end up on the stdin
+/// of whatever sudo executes. The password is zeroized when the last handle
+/// is dropped.
+pub struct Sudo {
+    /// `None` when sudo authenticated on its own, either through its own
+    /// terminal prompt or because no password is required.
+    password: Option<Zeroizing<String>>,
+}
+
+impl Sudo {
+    /// Checks `password` with `sudo -k -v`, ignoring any cached credential so
+    /// a wrong password is never accepted.
+    pub fn validate(password: Zeroizing<String>) -> Result<Self> {
+        let sudo = Self { password: Some(password) };
+        sudo.authenticate(&["-k"])?;
+        Ok(sudo)
+    }
+
+    /// Uses an existing sudo timestamp or a `NOPASSWD` rule, if there is one.
+    pub fn cached() -> Option<Self> {
+        let status = Command::new("sudo")
+            .args(["-n", "-v"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .ok()?;
+        status.success().then_some(Self { password: None })
+    }
+
+    /// Lets `sudo -v` prompt on the controlling terminal itself. Used by the
+    /// headless CLI, which has no password field of its own.
+    pub fn prompt() -> Result<Self> {
+        let status = Command::new("sudo").arg("-v").status()?;
+        if !status.success() {
+            return Err(eyre!("sudo authentication failed"));
+        }
+        Ok(Self { password: None })
+    }
+
+    /// Extends the sudo timestamp, re-authenticating if it has expired.
+    pub fn refresh(&self) -> Result<()> {
+        self.authenticate(&[])
+    }
+
+    /// Keeps the sudo timestamp alive until the returned guard is dropped.
+    pub fn keepalive(self: &Arc<Self>) -> KeepAlive {
+        let stop = Arc::new(AtomicBool::new(false));
+        let sudo = Arc::clone(self);
+        let thread_stop = Arc::clone(&stop);
+        let handle = thread::spawn(move || {
+            let mut next = Instant::now() + KEEPALIVE_INTERVAL;
+            while !thread_stop.load(Ordering::Relaxed) {
+                thread::park_timeout(next.saturating_duration_since(Instant::now()));
+                if Instant::now() >= next {
+                    let _ = sudo.refresh();
+                    next = Instant::now() + KEEPALIVE_INTERVAL;
+                }
+            }
+        });
+        KeepAlive { stop, handle: Some(handle) }
+    }
+
+    fn authenticate(&self, extra_args: &[&str]) -> Result<()> {
+        let Some(password) = &self.password else {
+            let status = Command::new("sudo")
+                .args(["-n", "-v"])
+                .stdin(Stdio::null())
+                .stderr(Stdio::null())
+                .status()?;
+            if !status.success() {
+                return Err(eyre!("sudo credentials expired and no password is available"));
+            }
+            return Ok(());
+        };
+
+        let mut child = Command::new("sudo")
+            .args(extra_args)
+            .args(["-S", "-v", "-p", ""])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        {
+            let mut stdin = child.stdin.take().expect("stdin is piped");
+            // sudo may exit before reading, so a broken pipe here is reported
+            // through the exit status instead.
+            let _ = stdin
+                .write_all(password.as_bytes())
+                .and_then(|_| stdin.write_all(b"\n"));
+        }
+
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let reason = stderr.lines().last().unwrap_or("incorrect password");
+            return Err(eyre!("sudo authentication failed: {}", reason.trim()));
+        }
+        Ok(())
+    }
+}
+
+/// Stops the keepalive thread started by [`Sudo::keepalive`] when dropped.
+pub struct KeepAlive {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for KeepAlive {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
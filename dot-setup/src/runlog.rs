@@ -0,0 +1,348 @@
+//! Logs of every run, kept in `$XDG_STATE_HOME/dot-setup/runs/<run-id>/`:
+//! the combined output in `run.log`, one log per step under `steps/`, the
+//! tasks as they were run in `config.toml`, and timings and exit codes in
+//! `summary.json`.
+
+use crate::config::Task;
+use crate::engine::{Reporter, StepStatus};
+use crate::exec::Termination;
+use crate::state;
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+pub fn runs_dir() -> PathBuf {
+    state::state_dir().join("runs")
+}
+
+/// How a run ended.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl Outcome {
+    /// Failed when any step failed, cancelled when some were cancelled or
+    /// never started.
+    pub fn of(statuses: &[StepStatus]) -> Self {
+        if statuses.contains(&StepStatus::Failed) {
+            Outcome::Failed
+        } else if statuses.iter().any(|s| matches!(s, StepStatus::Pending | StepStatus::Cancelled)) {
+            Outcome::Cancelled
+        } else {
+            Outcome::Completed
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StepSummary {
+    pub id: String,
+    pub name: String,
+    pub status: StepStatus,
+    /// Seconds from the start of the run until the step started.
+    #[serde(default)]
+    pub started: Option<f64>,
+    /// How long the step took, in seconds.
+    #[serde(default)]
+    pub duration: Option<f64>,
+    /// How the step's last command ended.
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    #[serde(default)]
+    pub signal: Option<i32>,
+    #[serde(default)]
+    pub error: Vec<String>,
+    /// The step's log, relative to the run directory.
+    pub log: String,
+}
+
+/// `summary.json` of a run. It is rewritten as steps finish, so a run that
+/// was killed still has one, without `finished` and `outcome`.
+#[derive(Serialize, Deserialize)]
+pub struct Summary {
+    pub id: String,
+    /// Unix time.
+    pub started: u64,
+    #[serde(default)]
+    pub finished: Option<u64>,
+    #[serde(default)]
+    pub outcome: Option<Outcome>,
+    #[serde(default)]
+    pub profile: Option<String>,
+    pub steps: Vec<StepSummary>,
+}
+
+impl Summary {
+    /// `completed`, `failed`, `cancelled`, or `unfinished` for a run that
+    /// is still going or was killed.
+    pub fn outcome_name(&self) -> &'static str {
+        match self.outcome {
+            Some(Outcome::Completed) => "completed",
+            Some(Outcome::Failed) => "failed",
+            Some(Outcome::Cancelled) => "cancelled",
+            None => "unfinished",
+        }
+    }
+
+    pub fn duration(&self) -> Option<u64> {
+        self.finished.map(|finished| finished.saturating_sub(self.started))
+    }
+
+    /// The steps that failed or never finished, in run order.
+    pub fn unfinished(&self) -> impl Iterator<Item = &StepSummary> {
+        self.steps.iter().filter(|step| !step.status.is_done())
+    }
+}
+
+/// The settings a run used, saved next to its logs as `config.toml`.
+#[derive(Serialize)]
+pub struct Resolved<'a> {
+    /// Where the config was read from.
+    pub source: String,
+    pub profile: Option<String>,
+    pub jobs: usize,
+    /// The selected tasks, with unchecked packages removed.
+    pub tasks: &'a [Task],
+}
+
+struct State {
+    summary: Summary,
+    combined: Option<File>,
+    steps: Vec<Option<File>>,
+    started: Vec<Option<Instant>>,
+}
+
+/// A [`Reporter`] that writes everything to the run directory before
+/// passing it on. Failing to write a log never fails the run.
+pub struct RunLog {
+    dir: PathBuf,
+    start: Instant,
+    inner: Arc<dyn Reporter>,
+    state: Mutex<State>,
+}
+
+impl RunLog {
+    /// Creates a new run directory for `resolved.tasks`.
+    pub fn create(resolved: &Resolved, inner: Arc<dyn Reporter>) -> Result<Arc<Self>> {
+        let (id, dir) = new_run_dir()?;
+        fs::create_dir_all(dir.join("steps"))?;
+        fs::write(dir.join("config.toml"), toml::to_string(resolved)?)?;
+
+        let steps = resolved
+            .tasks
+            .iter()
+            .enumerate()
+            .map(|(i, task)| StepSummary {
+                id: task.id.clone(),
+                name: task.name.clone(),
+                status: StepStatus::Pending,
+                started: None,
+                duration: None,
+                exit_code: None,
+                signal: None,
+                error: Vec::new(),
+                log: format!("steps/{:02}-{}.log", i + 1, task.id),
+            })
+            .collect();
+        let summary = Summary { id, started: unix_now(), finished: None, outcome: None, profile: resolved.profile.clone(), steps };
+        let state = State {
+            summary,
+            combined: File::create(dir.join("run.log")).ok(),
+            steps: resolved.tasks.iter().map(|_| None).collect(),
+            started: vec![None; resolved.tasks.len()],
+        };
+
+        let log = Self { dir, start: Instant::now(), inner, state: Mutex::new(state) };
+        log.save(&log.state.lock().unwrap().summary);
+        Ok(Arc::new(log))
+    }
+
+    pub fn id(&self) -> String {
+        self.state.lock().unwrap().summary.id.clone()
+    }
+
+    pub fn dir(&self) -> &PathBuf {
+        &self.dir
+    }
+
+    /// Records the final statuses once the engine returns.
+    pub fn finish(&self, statuses: &[StepStatus]) {
+        let mut state = self.state.lock().unwrap();
+        for (step, &status) in state.summary.steps.iter_mut().zip(statuses) {
+            step.status = status;
+        }
+        state.summary.finished = Some(unix_now());
+        state.summary.outcome = Some(Outcome::of(statuses));
+        self.save(&state.summary);
+    }
+
+    fn save(&self, summary: &Summary) {
+        if let Ok(json) = serde_json::to_string_pretty(summary) {
+            let _ = fs::write(self.dir.join("summary.json"), json);
+        }
+    }
+
+    /// Writes `line` to the combined log and, when given, a step's log.
+    fn write(&self, state: &mut State, index: Option<usize>, line: &str) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        if let Some(file) = &mut state.combined {
+            let _ = match index {
+                Some(i) => writeln!(file, "[{:8.1}s] [{}] {}", elapsed, state.summary.steps[i].id, line),
+                None => writeln!(file, "[{:8.1}s] {}", elapsed, line),
+            };
+        }
+        if let Some(Some(file)) = index.map(|i| &mut state.steps[i]) {
+            let _ = writeln!(file, "[{:8.1}s] {}", elapsed, line);
+        }
+    }
+}
+
+impl Reporter for RunLog {
+    fn task_started(&self, index: usize, task: &Task) {
+        {
+            let mut state = self.state.lock().unwrap();
+            let path = self.dir.join(&state.summary.steps[index].log);
+            state.steps[index] = File::create(path).ok();
+            state.started[index] = Some(Instant::now());
+            let step = &mut state.summary.steps[index];
+            step.status = StepStatus::Running;
+            step.started = Some(self.start.elapsed().as_secs_f64());
+            self.write(&mut state, None, &format!("=== {} ===", task.name));
+            self.save(&state.summary);
+        }
+        self.inner.task_started(index, task);
+    }
+
+    fn output(&self, index: usize, line: String) {
+        self.write(&mut self.state.lock().unwrap(), Some(index), &line);
+        self.inner.output(index, line);
+    }
+
+    fn task_finished(&self, index: usize, task: &Task, status: StepStatus, error: &[String]) {
+        {
+            let mut state = self.state.lock().unwrap();
+            for line in crate::engine::status_lines(task, status, error) {
+                self.write(&mut state, Some(index), &line);
+            }
+            let duration = state.started[index].map(|started| started.elapsed().as_secs_f64());
+            let step = &mut state.summary.steps[index];
+            step.status = status;
+            step.duration = duration;
+            step.error = error.to_vec();
+            state.steps[index] = None;
+            self.save(&state.summary);
+        }
+        self.inner.task_finished(index, task, status, error);
+    }
+
+    fn command_finished(&self, index: usize, command: &str, termination: Termination) {
+        {
+            let mut state = self.state.lock().unwrap();
+            self.write(&mut state, Some(index), &format!("-- `{}`: {}", command, termination));
+            let step = &mut state.summary.steps[index];
+            (step.exit_code, step.signal) = match termination {
+                Termination::Code(code) => (Some(code), None),
+                Termination::Signal(signal) => (None, Some(signal)),
+                Termination::TimedOut(_) => (None, None),
+            };
+        }
+        self.inner.command_finished(index, command, termination);
+    }
+}
+
+/// A fresh directory under [`runs_dir`] named after the current time.
+fn new_run_dir() -> Result<(String, PathBuf)> {
+    fs::create_dir_all(runs_dir())?;
+    let base = state::run_id();
+    let mut id = base.clone();
+    let mut n = 1;
+    loop {
+        let dir = runs_dir().join(&id);
+        match fs::create_dir(&dir) {
+            Ok(()) => return Ok((id, dir)),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                n += 1;
+                id = format!("{}-{}", base, n);
+            }
+            Err(err) => return Err(err).wrap_err_with(|| format!("could not create {}", dir.display())),
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Every run with a readable summary, oldest first.
+pub fn runs() -> Vec<Summary> {
+    let mut runs: Vec<Summary> = fs::read_dir(runs_dir())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| fs::read_to_string(entry.path().join("summary.json")).ok())
+        .filter_map(|content| serde_json::from_str(&content).ok())
+        .collect();
+    runs.sort_by(|a, b| state::run_id_order(&a.id).cmp(&state::run_id_order(&b.id)));
+    runs
+}
+
+/// The summary of run `id`, or of the latest run for `last`.
+pub fn find(id: &str) -> Result<Summary> {
+    let runs = runs();
+    let run = match id {
+        "last" => runs.into_iter().last(),
+        id => runs.into_iter().find(|run| run.id == id),
+    };
+    run.ok_or_else(|| eyre!("no run `{}` in {}; see `dot-setup history`", id, runs_dir().display()))
+}
+
+/// The latest run, when it failed, was cancelled or was killed, so its
+/// unfinished steps can be picked up again.
+pub fn resumable() -> Option<Summary> {
+    runs().pop().filter(|run| run.unfinished().next().is_some())
+}
+
+/// The combined log of `run`, or the log of its step `step`.
+pub fn read_log(run: &Summary, step: Option<&str>) -> Result<String> {
+    let file = match step {
+        None => "run.log".to_string(),
+        Some(id) => {
+            let step = run.steps.iter().find(|s| s.id == id).ok_or_else(|| {
+                let ids: Vec<&str> = run.steps.iter().map(|s| s.id.as_str()).collect();
+                eyre!("run {} has no step `{}` (steps: {})", run.id, id, ids.join(", "))
+            })?;
+            step.log.clone()
+        }
+    };
+    let path = runs_dir().join(&run.id).join(file);
+    match fs::read_to_string(&path) {
+        Ok(content) => Ok(content),
+        // Steps that never started have no log.
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound && step.is_some() => Ok(String::new()),
+        Err(err) => Err(err).wrap_err_with(|| format!("could not read {}", path.display())),
+    }
+}
+
+/// `42s`, `3m05s` or `1h02m`.
+pub struct Elapsed(pub u64);
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.0;
+        match secs {
+            0..=59 => write!(f, "{}s", secs),
+            60..=3599 => write!(f, "{}m{:02}s", secs / 60, secs % 60),
+            _ => write!(f, "{}h{:02}m", secs / 3600, secs % 3600 / 60),
+        }
+    }
+}
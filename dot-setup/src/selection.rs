@@ -0,0 +1,123 @@
+use crate::config::Task;
+use crate::state;
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::PathBuf;
+
+/// The choices made on the selection screen, remembered across launches.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Selection {
+    /// Task id to whether it is checked, for tasks toggled away from their
+    /// config default.
+    #[serde(default)]
+    pub enabled: BTreeMap<String, bool>,
+    /// Task id to the packages/apps unchecked inside it.
+    #[serde(default)]
+    pub excluded: BTreeMap<String, BTreeSet<String>>,
+    /// The profile picked in the header, used when `--profile` isn't given.
+    #[serde(default)]
+    pub profile: Option<String>,
+}
+
+impl Selection {
+    fn path() -> PathBuf {
+        state::state_dir().join("selection.json")
+    }
+
+    /// The remembered selection, or an empty one if there is none yet.
+    pub fn load() -> Self {
+        fs::read_to_string(Self::path())
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Applies remembered task toggles on top of the config defaults.
+    pub fn restore(&self, tasks: &mut [Task]) {
+        for task in tasks {
+            if let Some(&enabled) = self.enabled.get(&task.id) {
+                task.enabled = enabled;
+            }
+        }
+    }
+
+    /// Records the current task toggles, dropping exclusions for items that
+    /// no longer exist in config.toml.
+    pub fn record(&mut self, tasks: &[Task]) {
+        self.enabled = tasks.iter().map(|t| (t.id.clone(), t.enabled)).collect();
+        for task in tasks {
+            if let Some(excluded) = self.excluded.get_mut(&task.id) {
+                let items = task.kind.items();
+                excluded.retain(|item| items.contains(&item.as_str()));
+            }
+        }
+        self.excluded.retain(|_, excluded| !excluded.is_empty());
+    }
+
+    pub fn is_excluded(&self, task: &Task, item: &str) -> bool {
+        self.excluded.get(&task.id).is_some_and(|excluded| excluded.contains(item))
+    }
+
+    pub fn toggle_item(&mut self, task: &Task, item: &str) {
+        let excluded = self.excluded.entry(task.id.clone()).or_default();
+        if !excluded.remove(item) {
+            excluded.insert(item.to_string());
+        }
+    }
+
+    /// A copy of `task` without the unchecked items.
+    pub fn apply(&self, task: &Task) -> Task {
+        let mut task = task.clone();
+        let excluded = self.excluded.get(&task.id).cloned().unwrap_or_default();
+        task.kind.retain_items(|item| !excluded.contains(item));
+        task
+    }
+}
+
+/// One line of the selection tree.
+#[derive(Clone, PartialEq)]
+pub enum Row {
+    Task(usize),
+    Item(usize, String),
+    Confirm,
+}
+
+/// Flattens the task tree into rows. Tasks in `expanded` show their items;
+/// a non-empty `filter` (case-insensitive) expands every task and keeps only
+/// matching items, plus tasks whose name matches.
+pub fn rows(tasks: &[Task], expanded: &BTreeSet<String>, filter: &str) -> Vec<Row> {
+    let filter = filter.to_lowercase();
+    let mut rows = Vec::new();
+
+    for (i, task) in tasks.iter().enumerate() {
+        let items: Vec<&str> = task
+            .kind
+            .items()
+            .into_iter()
+            .filter(|item| filter.is_empty() || item.to_lowercase().contains(&filter))
+            .collect();
+
+        if !filter.is_empty() && items.is_empty() && !task.name.to_lowercase().contains(&filter) {
+            continue;
+        }
+
+        rows.push(Row::Task(i));
+        if !filter.is_empty() || expanded.contains(&task.id) {
+            rows.extend(items.into_iter().map(|item| Row::Item(i, item.to_string())));
+        }
+    }
+
+    rows.push(Row::Confirm);
+    rows
+}
@@ -0,0 +1,96 @@
+use std::env;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where dot-setup keeps data between runs: `$XDG_STATE_HOME/dot-setup`,
+/// falling back to `~/.local/state/dot-setup`.
+pub fn state_dir() -> PathBuf {
+    let base = env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| home_dir().join(".local/state"));
+    base.join("dot-setup")
+}
+
+/// `$XDG_CONFIG_HOME`, falling back to `~/.config`.
+pub fn config_home() -> PathBuf {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| home_dir().join(".config"))
+}
+
+pub fn home_dir() -> PathBuf {
+    env::var_os("HOME").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("/"))
+}
+
+/// Expands a leading `~`, `$HOME` or `${HOME}` the way the shell would.
+pub fn expand_home(path: &str) -> PathBuf {
+    for prefix in ["~", "$HOME", "${HOME}"] {
+        if let Some(rest) = path.strip_prefix(prefix) {
+            if rest.is_empty() || rest.starts_with('/') {
+                return home_dir().join(rest.trim_start_matches('/'));
+            }
+        }
+    }
+    PathBuf::from(path)
+}
+
+/// A sortable UTC timestamp such as `20261018T113045Z`, used to name the
+/// directories a run writes to.
+pub fn run_id() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Sorts run ids by their timestamp, then by the `-N` suffix a run started
+/// within the same second as another gets, so that `…Z-10` comes after
+/// `…Z-2` rather than before it.
+pub fn run_id_order(id: &str) -> (&str, u32) {
+    match id.rsplit_once('-').map(|(base, n)| (base, n.parse())) {
+        Some((base, Ok(n))) => (base, n),
+        // Suffixes start at 2.
+        _ => (id, 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_ids_sort_by_timestamp_then_suffix() {
+        let mut ids = ["20261018T113045Z-10", "20261018T113046Z", "20261018T113045Z-2", "20261018T113045Z"];
+        ids.sort_by(|a, b| run_id_order(a).cmp(&run_id_order(b)));
+        assert_eq!(ids, ["20261018T113045Z", "20261018T113045Z-2", "20261018T113045Z-10", "20261018T113046Z"]);
+    }
+
+    #[test]
+    fn run_id_is_a_utc_timestamp() {
+        let id = run_id();
+        assert_eq!(id.len(), "20261018T113045Z".len());
+        assert_eq!(&id[8..9], "T");
+        assert!(id.ends_with('Z'));
+    }
+}
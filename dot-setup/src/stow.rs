@@ -0,0 +1,1061 @@
+//! A native implementation of `stow --dotfiles`: links every entry of a
+//! package directory into the target directory, honoring
+//! `.stow-local-ignore` and translating a `dot-` prefix into `.`. Entries
+//! ending in `.tmpl` are rendered into real files instead (see
+//! [`template`](crate::template)).
+
+use crate::config::{Resolution, StowTask};
+use crate::state;
+use crate::template::{self, RenderedFiles, Renderer};
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::Result;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::symlink;
+use std::path::{Component, Path, PathBuf};
+
+const IGNORE_FILE: &str = ".stow-local-ignore";
+
+/// stow's built-in ignore list, used when the package has no
+/// `.stow-local-ignore`.
+const DEFAULT_IGNORE: &[&str] = &[
+    "RCS", ".+,v", "CVS", r"\.\#.+", r"\.cvsignore", r"\.svn", "_darcs", r"\.hg", r"\.git", r"\.gitignore",
+    r"\.gitmodules", ".+~", r"\#.*\#", "^/README.*", "^/LICENSE.*", "^/COPYING",
+];
+
+/// What stowing does at one target path.
+pub enum Action {
+    /// Create `target` as a symlink to `source`.
+    Link { source: PathBuf, target: PathBuf },
+    /// The target already links to the source.
+    Linked,
+    /// Write the template `source` rendered as `content` to `target`, either
+    /// for the first time or because the template or its variables changed.
+    Render { source: PathBuf, target: PathBuf, content: String },
+    /// The target already holds the rendered template.
+    Rendered,
+    Conflict(Conflict),
+}
+
+/// Something other than our link or rendered file already sits at `target`.
+#[derive(Clone)]
+pub struct Conflict {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    /// What is in the way, e.g. "already exists".
+    pub reason: String,
+    /// Whether the existing file can replace the repository's copy, i.e.
+    /// both are regular files and the source isn't a template.
+    pub adoptable: bool,
+    /// For templates, the rendered content written once the way is clear.
+    pub render: Option<String>,
+}
+
+/// Everything a stow task would do, computed without touching the target.
+pub struct Plan {
+    /// `ensure_dirs` entries that don't exist yet, and directories that
+    /// can't be folded into one link because they contain templates.
+    pub create_dirs: Vec<PathBuf>,
+    pub actions: Vec<Action>,
+}
+
+impl Plan {
+    pub fn conflicts(&self) -> impl Iterator<Item = &Conflict> {
+        self.actions.iter().filter_map(|action| match action {
+            Action::Conflict(conflict) => Some(conflict),
+            _ => None,
+        })
+    }
+
+    /// Whether every link and rendered file is already in place.
+    pub fn is_satisfied(&self) -> bool {
+        self.create_dirs.is_empty()
+            && self.actions.iter().all(|action| matches!(action, Action::Linked | Action::Rendered))
+    }
+}
+
+/// The package directory of a stow task, ready to be walked.
+struct Package {
+    root: PathBuf,
+    target: PathBuf,
+    ignore: Ignore,
+    renderer: Renderer,
+    rendered: RenderedFiles,
+}
+
+/// One entry of the package and where it goes.
+struct Entry {
+    rel: PathBuf,
+    source: PathBuf,
+    target: PathBuf,
+    template: bool,
+}
+
+impl Package {
+    fn open(stow: &StowTask) -> Result<Self> {
+        if stow.dir.is_relative() {
+            return Err(eyre!(
+                "stow directory `{}` is relative, but the config isn't in a checkout of the repository \
+                 (it's the built-in default or in $XDG_CONFIG_HOME), so there is nothing to resolve it \
+                 against; pass --config with the repository's config.toml or set an absolute `dir`",
+                stow.dir.display()
+            ));
+        }
+        let root = stow
+            .dir
+            .canonicalize()
+            .wrap_err_with(|| format!("stow directory {} not found", stow.dir.display()))?;
+        let package = Package {
+            ignore: Ignore::load(&root)?,
+            root,
+            target: state::expand_home(&stow.target),
+            renderer: Renderer::new(&stow.vars),
+            rendered: RenderedFiles::load(),
+        };
+        package.check_separate()?;
+        Ok(package)
+    }
+
+    /// Refuses a target that is the package directory or lies inside it,
+    /// and a package entry that would be linked over the package itself or
+    /// a directory holding it: either way the links would point at
+    /// themselves. A package inside the target, like `~/dotfiles/home`
+    /// stowed into `~`, is fine otherwise.
+    fn check_separate(&self) -> Result<()> {
+        let target = self.target.canonicalize().unwrap_or_else(|_| normalize(&self.target));
+        let overlap = if target.starts_with(&self.root) {
+            Some(target.clone())
+        } else if self.root.starts_with(&target) {
+            self.entries(Path::new(""), &target)?
+                .into_iter()
+                .map(|entry| entry.target)
+                .find(|path| self.root.starts_with(path))
+        } else {
+            None
+        };
+        match overlap {
+            Some(path) => Err(eyre!(
+                "stow directory {} and target {} overlap at {}; stowing would link the package into itself",
+                self.root.display(),
+                target.display(),
+                path.display()
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// The entries of the package directory `rel` that aren't ignored,
+    /// sorted by name.
+    fn entries(&self, rel: &Path, target_dir: &Path) -> Result<Vec<Entry>> {
+        let dir = self.root.join(rel);
+        let mut names: Vec<_> = fs::read_dir(&dir)
+            .wrap_err_with(|| format!("could not read {}", dir.display()))?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name())
+            .collect();
+        names.sort();
+
+        Ok(names
+            .iter()
+            .filter_map(|name| {
+                let name = name.to_string_lossy();
+                let rel = rel.join(name.as_ref());
+                if self.ignore.matches(&rel, &name) {
+                    return None;
+                }
+                let source = self.root.join(&rel);
+                let target_name = dotfile_name(&name);
+                let template = source.is_file() && target_name.len() > template::SUFFIX.len();
+                let (target_name, template) = match target_name.strip_suffix(template::SUFFIX) {
+                    Some(stripped) if template => (stripped.to_string(), true),
+                    _ => (target_name, false),
+                };
+                Some(Entry { rel, source, target: target_dir.join(target_name), template })
+            })
+            .collect())
+    }
+
+    /// Whether the package directory `rel` has a template anywhere below it.
+    fn has_templates(&self, rel: &Path) -> Result<bool> {
+        for entry in self.entries(rel, Path::new(""))? {
+            if entry.template || (entry.source.is_dir() && self.has_templates(&entry.rel)?) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// Walks the package directory and decides, for each entry, whether it
+/// needs a link (or rendering), is already in place, or conflicts with
+/// what is there. Like stow, a directory that doesn't exist in the target
+/// yet is linked as a whole ("folded") unless it is one of `ensure_dirs`,
+/// contains one, or contains a template.
+pub fn plan(stow: &StowTask) -> Result<Plan> {
+    let package = Package::open(stow)?;
+    let ensured: BTreeSet<PathBuf> = stow.ensure_dirs.iter().map(|dir| state::expand_home(dir)).collect();
+    let mut plan = Plan {
+        create_dirs: ensured.iter().filter(|dir| !dir.exists()).cloned().collect(),
+        actions: Vec::new(),
+    };
+    walk(&package, Path::new(""), &package.target, &ensured, &mut plan)?;
+    Ok(plan)
+}
+
+fn walk(package: &Package, rel: &Path, target_dir: &Path, ensured: &BTreeSet<PathBuf>, plan: &mut Plan) -> Result<()> {
+    for entry in package.entries(rel, target_dir)? {
+        let Entry { rel, source, target, template } = entry;
+        let source_is_dir = source.is_dir();
+        let render = if template { Some(package.renderer.render(&source)?) } else { None };
+
+        match fs::symlink_metadata(&target) {
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                if source_is_dir && ensured.iter().any(|dir| dir.starts_with(&target)) {
+                    walk(package, &rel, &target, ensured, plan)?;
+                } else if source_is_dir && package.has_templates(&rel)? {
+                    plan.create_dirs.push(target.clone());
+                    walk(package, &rel, &target, ensured, plan)?;
+                } else if let Some(content) = render {
+                    plan.actions.push(Action::Render { source, target, content });
+                } else {
+                    plan.actions.push(Action::Link { source, target });
+                }
+            }
+            Err(err) => return Err(err).wrap_err_with(|| format!("could not inspect {}", target.display())),
+            Ok(meta) if meta.file_type().is_symlink() => {
+                let points_to = fs::read_link(&target)?;
+                if render.is_none() && fs::canonicalize(&target).ok().as_deref() == Some(source.as_path()) {
+                    plan.actions.push(Action::Linked);
+                } else {
+                    let reason = if target.exists() {
+                        format!("is a symlink to {}", points_to.display())
+                    } else {
+                        format!("is a dangling symlink to {}", points_to.display())
+                    };
+                    plan.actions.push(Action::Conflict(Conflict { source, target, reason, adoptable: false, render }));
+                }
+            }
+            Ok(meta) if meta.is_dir() => {
+                if source_is_dir {
+                    walk(package, &rel, &target, ensured, plan)?;
+                } else {
+                    let reason = "is a directory".to_string();
+                    plan.actions.push(Action::Conflict(Conflict { source, target, reason, adoptable: false, render }));
+                }
+            }
+            Ok(meta) => match render {
+                Some(content) if fs::read(&target).is_ok_and(|current| current == content.as_bytes()) => {
+                    plan.actions.push(Action::Rendered);
+                }
+                // Our own earlier output: the template or its variables changed.
+                Some(content) if package.rendered.is_untouched(&target) => {
+                    plan.actions.push(Action::Render { source, target, content });
+                }
+                Some(content) => {
+                    let reason = if package.rendered.files.contains_key(&target) {
+                        "was edited after it was rendered"
+                    } else {
+                        "already exists"
+                    };
+                    let reason = reason.to_string();
+                    let render = Some(content);
+                    plan.actions.push(Action::Conflict(Conflict { source, target, reason, adoptable: false, render }));
+                }
+                None => {
+                    let reason = "already exists".to_string();
+                    let adoptable = meta.is_file() && source.is_file();
+                    plan.actions.push(Action::Conflict(Conflict { source, target, reason, adoptable, render }));
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Carries out `plan`, resolving each conflict with the choice recorded in
+/// `stow.resolutions` or else `stow.on_conflict`. Every change is passed to
+/// `report` as it happens and recorded in a [`Manifest`] under a new run id,
+/// so that `dot-setup restore` can undo it.
+pub fn apply(stow: &StowTask, plan: &Plan, report: &dyn Fn(String)) -> Result<()> {
+    let run_id = new_run_id();
+    let mut manifest = Manifest::default();
+    let mut rendered = RenderedFiles::load();
+    let result = apply_actions(stow, plan, &backup_dir(&run_id), &mut manifest, &mut rendered, report);
+
+    if !manifest.rendered.is_empty() {
+        rendered.save()?;
+    }
+    if !manifest.is_empty() {
+        manifest.save(&run_id)?;
+        report(format!("undo with `dot-setup restore {}`", run_id));
+    }
+    result
+}
+
+fn apply_actions(
+    stow: &StowTask,
+    plan: &Plan,
+    backup_dir: &Path,
+    manifest: &mut Manifest,
+    rendered: &mut RenderedFiles,
+    report: &dyn Fn(String),
+) -> Result<()> {
+    let target_root = state::expand_home(&stow.target);
+
+    for dir in &plan.create_dirs {
+        // Record the outermost missing ancestor too, so restoring can
+        // remove everything this created.
+        let mut created: Vec<PathBuf> = dir.ancestors().take_while(|d| !d.exists()).map(Path::to_path_buf).collect();
+        fs::create_dir_all(dir).wrap_err_with(|| format!("could not create {}", dir.display()))?;
+        report(format!("created {}", dir.display()));
+        created.reverse();
+        manifest.created_dirs.extend(created);
+    }
+
+    for action in &plan.actions {
+        let (source, target, render) = match action {
+            Action::Linked | Action::Rendered => continue,
+            Action::Link { source, target } => (source, target, None),
+            Action::Render { source, target, content } => (source, target, Some(content)),
+            Action::Conflict(conflict) => {
+                let resolution = stow.resolutions.get(&conflict.target).copied().unwrap_or(stow.on_conflict);
+                match resolution {
+                    Resolution::Skip => {
+                        report(format!("skipped {}: {}", conflict.target.display(), conflict.reason));
+                        continue;
+                    }
+                    Resolution::Backup => {
+                        let rel = conflict.target.strip_prefix(&target_root).unwrap_or(&conflict.target);
+                        let backup = backup_dir.join(rel.strip_prefix("/").unwrap_or(rel));
+                        if let Some(parent) = backup.parent() {
+                            fs::create_dir_all(parent)?;
+                        }
+                        move_path(&conflict.target, &backup)?;
+                        report(format!("backed up {} to {}", conflict.target.display(), backup.display()));
+                        manifest.backups.push(BackedUp { original: conflict.target.clone(), backup });
+                    }
+                    Resolution::Adopt if conflict.adoptable => {
+                        move_path(&conflict.target, &conflict.source)?;
+                        report(format!("adopted {} into {}", conflict.target.display(), conflict.source.display()));
+                    }
+                    Resolution::Adopt => {
+                        report(format!(
+                            "skipped {}: {} and can't be adopted",
+                            conflict.target.display(),
+                            conflict.reason
+                        ));
+                        continue;
+                    }
+                }
+                (&conflict.source, &conflict.target, conflict.render.as_ref())
+            }
+        };
+        match render {
+            Some(content) => {
+                fs::write(target, content).wrap_err_with(|| format!("could not write {}", target.display()))?;
+                report(format!("rendered {} from {}", target.display(), source.display()));
+                rendered.record(target, source, content);
+                manifest.rendered.push(target.clone());
+            }
+            None => {
+                link(source, target, report)?;
+                manifest.links.push(CreatedLink { target: target.clone(), source: source.clone() });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The lines a dry run prints, besides the commands, for the files that
+/// would be rendered and for each conflict and how it would be resolved.
+pub fn describe(stow: &StowTask, plan: &Plan) -> Vec<String> {
+    plan.actions
+        .iter()
+        .filter_map(|action| match action {
+            Action::Render { source, target, .. } => {
+                Some(format!("  render: {} from {}", target.display(), source.display()))
+            }
+            Action::Conflict(conflict) => {
+                let resolution = stow.resolutions.get(&conflict.target).copied().unwrap_or(stow.on_conflict);
+                Some(format!("  conflict: {} {} ({})", conflict.target.display(), conflict.reason, resolution.name()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// What sits where a package entry's link should be.
+#[derive(Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum LinkState {
+    Linked,
+    Missing,
+    /// A real file or directory is in the way.
+    Shadowed,
+    /// A symlink to something other than the package entry.
+    Elsewhere { to: PathBuf },
+    /// A symlink whose destination doesn't exist.
+    Dangling { to: PathBuf },
+    /// A rendered template, up to date.
+    Rendered,
+    /// A rendered template that an older template or older variables
+    /// produced.
+    Outdated,
+    /// A rendered template that was edited afterwards.
+    Edited,
+}
+
+impl fmt::Display for LinkState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkState::Linked => f.write_str("linked"),
+            LinkState::Missing => f.write_str("missing"),
+            LinkState::Shadowed => f.write_str("shadowed by a real file"),
+            LinkState::Elsewhere { to } => write!(f, "points to {}", to.display()),
+            LinkState::Dangling { to } => write!(f, "dangling link to {}", to.display()),
+            LinkState::Rendered => f.write_str("rendered"),
+            LinkState::Outdated => f.write_str("rendered from an older template"),
+            LinkState::Edited => f.write_str("edited after it was rendered"),
+        }
+    }
+}
+
+/// The state of every target the package maps to. Directories that exist
+/// in the target are descended into; a folded directory link counts as one
+/// target.
+pub fn link_states(stow: &StowTask) -> Result<Vec<(PathBuf, LinkState)>> {
+    let package = Package::open(stow)?;
+    let mut states = Vec::new();
+    walk_states(&package, Path::new(""), &package.target, &mut states)?;
+    Ok(states)
+}
+
+fn walk_states(
+    package: &Package,
+    rel: &Path,
+    target_dir: &Path,
+    states: &mut Vec<(PathBuf, LinkState)>,
+) -> Result<()> {
+    for Entry { rel, source, target, template } in package.entries(rel, target_dir)? {
+        let state = match fs::symlink_metadata(&target) {
+            Err(_) => LinkState::Missing,
+            Ok(meta) if meta.file_type().is_symlink() => {
+                let to = fs::read_link(&target)?;
+                match fs::canonicalize(&target) {
+                    Ok(resolved) if resolved == source && !template => LinkState::Linked,
+                    Ok(_) => LinkState::Elsewhere { to },
+                    Err(_) => LinkState::Dangling { to },
+                }
+            }
+            Ok(meta) if meta.is_dir() && source.is_dir() => {
+                walk_states(package, &rel, &target, states)?;
+                continue;
+            }
+            Ok(meta) if meta.is_file() && template => {
+                let content = package.renderer.render(&source)?;
+                if fs::read(&target).is_ok_and(|current| current == content.as_bytes()) {
+                    LinkState::Rendered
+                } else if package.rendered.is_untouched(&target) {
+                    LinkState::Outdated
+                } else if package.rendered.files.contains_key(&target) {
+                    LinkState::Edited
+                } else {
+                    LinkState::Shadowed
+                }
+            }
+            Ok(_) => LinkState::Shadowed,
+        };
+        states.push((target, state));
+    }
+    Ok(())
+}
+
+/// What unstowing would remove and leave alone.
+pub struct UnstowPlan {
+    /// Symlinks that point into the package, and rendered templates nobody
+    /// edited since.
+    pub remove: Vec<PathBuf>,
+    /// Targets that are real files instead of links, with the reason they
+    /// are left alone.
+    pub kept: Vec<(PathBuf, &'static str)>,
+    /// Directories stow runs created according to their manifests,
+    /// innermost first; removed once they are empty.
+    pub dirs: Vec<PathBuf>,
+}
+
+impl UnstowPlan {
+    pub fn is_satisfied(&self) -> bool {
+        self.remove.is_empty() && !self.dirs.iter().any(|dir| is_empty_dir(dir))
+    }
+}
+
+/// Finds the links into the package that `stow -D` would remove: every
+/// symlink in the target tree, where a package entry goes, that resolves
+/// into the package. Links pointing anywhere else aren't ours and are
+/// ignored. Rendered templates are removed unless they were edited.
+pub fn unstow_plan(stow: &StowTask) -> Result<UnstowPlan> {
+    let package = Package::open(stow)?;
+    let target = &package.target;
+
+    let mut plan = UnstowPlan { remove: Vec::new(), kept: Vec::new(), dirs: Vec::new() };
+    walk_links(&package, Path::new(""), target, &mut plan)?;
+
+    let mut dirs = BTreeSet::new();
+    for run in backup_runs() {
+        if let Ok(manifest) = Manifest::load(&run) {
+            dirs.extend(manifest.created_dirs.into_iter().filter(|dir| dir.starts_with(target)));
+        }
+    }
+    // Reverse order puts children before their parents.
+    plan.dirs = dirs.into_iter().rev().collect();
+    Ok(plan)
+}
+
+fn walk_links(package: &Package, rel: &Path, target_dir: &Path, plan: &mut UnstowPlan) -> Result<()> {
+    for Entry { rel, source, target, template } in package.entries(rel, target_dir)? {
+        let Ok(meta) = fs::symlink_metadata(&target) else { continue };
+        let root = &package.root;
+
+        if meta.file_type().is_symlink() {
+            let points_into_package = match fs::canonicalize(&target) {
+                Ok(resolved) => resolved.starts_with(root),
+                // A dangling link left behind by a file since removed from the repo.
+                Err(_) => fs::read_link(&target)
+                    .map(|link| normalize(&target.parent().unwrap_or(Path::new("/")).join(link)).starts_with(root))
+                    .unwrap_or(false),
+            };
+            if points_into_package {
+                plan.remove.push(target);
+            }
+        } else if meta.is_dir() && source.is_dir() {
+            walk_links(package, &rel, &target, plan)?;
+        } else if template {
+            if package.rendered.is_untouched(&target) {
+                plan.remove.push(target);
+            } else if package.rendered.files.contains_key(&target) {
+                plan.kept.push((target, "was edited after it was rendered"));
+            }
+        } else if meta.is_file() && source.is_file() {
+            let same = fs::read(&target).ok() == fs::read(&source).ok();
+            let reason = if same { "is a copy, not a link" } else { "was modified in place" };
+            plan.kept.push((target, reason));
+        }
+    }
+    Ok(())
+}
+
+/// Removes the links and rendered files in `plan`, then the directories
+/// it lists that are empty afterwards, and reports the files it left alone.
+pub fn unstow(plan: &UnstowPlan, report: &dyn Fn(String)) -> Result<()> {
+    let mut rendered = RenderedFiles::load();
+    for path in &plan.remove {
+        fs::remove_file(path).wrap_err_with(|| format!("could not remove {}", path.display()))?;
+        report(format!("removed {}", path.display()));
+        rendered.files.remove(path);
+    }
+    rendered.save()?;
+    for dir in &plan.dirs {
+        if fs::remove_dir(dir).is_ok() {
+            report(format!("removed {}", dir.display()));
+        }
+    }
+    for (path, reason) in &plan.kept {
+        report(format!("left {} alone: it {}", path.display(), reason));
+    }
+    Ok(())
+}
+
+fn is_empty_dir(path: &Path) -> bool {
+    fs::read_dir(path).is_ok_and(|mut entries| entries.next().is_none())
+}
+
+/// Resolves `.` and `..` in an absolute path without touching the disk.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::CurDir => {}
+            other => normalized.push(other.as_os_str()),
+        }
+    }
+    normalized
+}
+
+/// What one stow run changed, saved as `manifest.json` next to its backups.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Manifest {
+    /// Directories created for `ensure_dirs`, outermost first.
+    #[serde(default)]
+    pub created_dirs: Vec<PathBuf>,
+    #[serde(default)]
+    pub links: Vec<CreatedLink>,
+    /// Files written from templates.
+    #[serde(default)]
+    pub rendered: Vec<PathBuf>,
+    #[serde(default)]
+    pub backups: Vec<BackedUp>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreatedLink {
+    pub target: PathBuf,
+    pub source: PathBuf,
+}
+
+/// A file that was in the way of a link and moved aside.
+#[derive(Serialize, Deserialize)]
+pub struct BackedUp {
+    pub original: PathBuf,
+    pub backup: PathBuf,
+}
+
+impl Manifest {
+    fn is_empty(&self) -> bool {
+        self.created_dirs.is_empty() && self.links.is_empty() && self.rendered.is_empty() && self.backups.is_empty()
+    }
+
+    fn save(&self, run_id: &str) -> Result<()> {
+        let dir = backup_dir(run_id);
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("manifest.json"), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    fn load(run_id: &str) -> Result<Self> {
+        let path = backup_dir(run_id).join("manifest.json");
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let runs = backup_runs();
+                let known = if runs.is_empty() { "none".to_string() } else { runs.join(", ") };
+                return Err(eyre!("no stow run `{}` (known runs: {})", run_id, known));
+            }
+            Err(err) => return Err(err).wrap_err_with(|| format!("could not read {}", path.display())),
+        };
+        serde_json::from_str(&content).wrap_err_with(|| format!("{} is not a valid manifest", path.display()))
+    }
+}
+
+fn backup_dir(run_id: &str) -> PathBuf {
+    state::state_dir().join("backups").join(run_id)
+}
+
+/// A run id whose backup directory isn't taken yet, even when two runs
+/// start within the same second.
+fn new_run_id() -> String {
+    let base = state::run_id();
+    let mut run_id = base.clone();
+    let mut n = 1;
+    while backup_dir(&run_id).exists() {
+        n += 1;
+        run_id = format!("{}-{}", base, n);
+    }
+    run_id
+}
+
+/// Ids of the stow runs that can be restored, oldest first.
+pub fn backup_runs() -> Vec<String> {
+    let mut runs: Vec<String> = fs::read_dir(state::state_dir().join("backups"))
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join("manifest.json").is_file())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    runs.sort_by(|a, b| state::run_id_order(a).cmp(&state::run_id_order(b)));
+    runs
+}
+
+/// Undoes the stow run `run_id`: removes the links and rendered files it
+/// created (if they are still as it left them), moves the backed up files
+/// back and removes the directories it created once they are empty. Files
+/// changed since then are left alone and reported.
+pub fn restore(run_id: &str, report: &dyn Fn(String)) -> Result<()> {
+    let manifest = Manifest::load(run_id)?;
+    let mut rendered = RenderedFiles::load();
+    for path in &manifest.rendered {
+        if rendered.is_untouched(path) {
+            fs::remove_file(path).wrap_err_with(|| format!("could not remove {}", path.display()))?;
+            rendered.files.remove(path);
+            report(format!("removed {}", path.display()));
+        } else if path.exists() {
+            report(format!("left {} alone: it changed after it was rendered", path.display()));
+        }
+    }
+    if !manifest.rendered.is_empty() {
+        rendered.save()?;
+    }
+
+    for link in &manifest.links {
+        if fs::canonicalize(&link.target).ok().as_deref() == Some(link.source.as_path()) {
+            fs::remove_file(&link.target).wrap_err_with(|| format!("could not remove {}", link.target.display()))?;
+            report(format!("removed link {}", link.target.display()));
+        } else if fs::symlink_metadata(&link.target).is_ok() {
+            report(format!("left {} alone: it no longer links into the repository", link.target.display()));
+        }
+    }
+
+    for backed_up in &manifest.backups {
+        if fs::symlink_metadata(&backed_up.original).is_ok() {
+            report(format!(
+                "left {} in {}: {} exists again",
+                backed_up.original.display(),
+                backed_up.backup.display(),
+                backed_up.original.display()
+            ));
+            continue;
+        }
+        if let Some(parent) = backed_up.original.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        move_path(&backed_up.backup, &backed_up.original)?;
+        report(format!("restored {}", backed_up.original.display()));
+    }
+
+    for dir in manifest.created_dirs.iter().rev() {
+        if fs::remove_dir(dir).is_ok() {
+            report(format!("removed {}", dir.display()));
+        }
+    }
+    Ok(())
+}
+
+/// Creates `target` as a link to `source`, relative like stow's.
+fn link(source: &Path, target: &Path, report: &dyn Fn(String)) -> Result<()> {
+    let parent = target.parent().unwrap_or(Path::new("/"));
+    let relative = relative_path(source, parent);
+    symlink(&relative, target).wrap_err_with(|| format!("could not link {}", target.display()))?;
+    report(format!("linked {} -> {}", target.display(), relative.display()));
+    Ok(())
+}
+
+/// Renames `from` to `to`, copying when they are on different filesystems.
+fn move_path(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::CrossesDevices && from.is_file() => {
+            fs::copy(from, to)?;
+            fs::remove_file(from)?;
+            Ok(())
+        }
+        Err(err) => Err(eyre!("could not move {} to {}: {}", from.display(), to.display(), err)),
+    }
+}
+
+/// `path` expressed relative to the directory `base`; both are absolute.
+fn relative_path(path: &Path, base: &Path) -> PathBuf {
+    let base = base.canonicalize().unwrap_or_else(|_| base.to_path_buf());
+    let path: Vec<Component> = path.components().collect();
+    let base: Vec<Component> = base.components().collect();
+    let common = path.iter().zip(&base).take_while(|(a, b)| a == b).count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..base.len() {
+        relative.push("..");
+    }
+    for component in &path[common..] {
+        relative.push(component.as_os_str());
+    }
+    relative
+}
+
+/// `dot-bashrc` → `.bashrc`, as `stow --dotfiles` does.
+fn dotfile_name(name: &str) -> String {
+    match name.strip_prefix("dot-") {
+        Some(rest) if !rest.is_empty() => format!(".{}", rest),
+        _ => name.to_string(),
+    }
+}
+
+/// The package's ignore list. Like stow, patterns containing a `/` are
+/// matched against the path from the package root (with a leading `/`),
+/// the others against the file name; both must match in full.
+struct Ignore {
+    paths: Vec<Regex>,
+    names: Vec<Regex>,
+}
+
+impl Ignore {
+    fn load(package: &Path) -> Result<Self> {
+        let path = package.join(IGNORE_FILE);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => DEFAULT_IGNORE.join("\n"),
+            Err(err) => return Err(err).wrap_err_with(|| format!("could not read {}", path.display())),
+        };
+
+        let mut ignore = Ignore { paths: Vec::new(), names: Vec::new() };
+        for (number, line) in content.lines().enumerate() {
+            let pattern = line.trim();
+            if pattern.is_empty() || pattern.starts_with('#') {
+                continue;
+            }
+            let regex = Regex::new(&format!("^(?:{})$", pattern))
+                .wrap_err_with(|| format!("{}:{}: invalid pattern `{}`", path.display(), number + 1, pattern))?;
+            if pattern.contains('/') {
+                ignore.paths.push(regex);
+            } else {
+                ignore.names.push(regex);
+            }
+        }
+        Ok(ignore)
+    }
+
+    fn matches(&self, rel: &Path, name: &str) -> bool {
+        let path = format!("/{}", rel.display());
+        name == IGNORE_FILE
+            || self.names.iter().any(|regex| regex.is_match(name))
+            || self.paths.iter().any(|regex| regex.is_match(&path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, MutexGuard, Once};
+
+    /// Serializes the tests that write manifests and `rendered.json`, which
+    /// all share one state directory.
+    static STATE: Mutex<()> = Mutex::new(());
+
+    fn state_lock() -> MutexGuard<'static, ()> {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            let dir = std::env::temp_dir().join(format!("dot-setup-test-{}-state", std::process::id()));
+            std::env::set_var("XDG_STATE_HOME", dir);
+        });
+        STATE.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// An empty directory for one test.
+    fn tempdir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dot-setup-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    fn write(path: &Path, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    fn stow_task(dir: &Path, target: &Path) -> StowTask {
+        toml::from_str(&format!(
+            "dir = {:?}\ntarget = {:?}\nvars = {{ name = \"world\" }}",
+            dir.display().to_string(),
+            target.display().to_string()
+        ))
+        .unwrap()
+    }
+
+    /// A package with a dotfile, a directory to fold and a template, and a
+    /// target with a file in the way of one of them.
+    fn fixture(name: &str) -> (PathBuf, PathBuf) {
+        let root = tempdir(name);
+        let (package, target) = (root.join("package"), root.join("home"));
+        write(&package.join("dot-bashrc"), "# bashrc\n");
+        write(&package.join("dot-config/app/app.conf"), "key = value\n");
+        write(&package.join("dot-greeting.tmpl"), "hello {{ name }}\n");
+        write(&package.join("README.md"), "not linked\n");
+        write(&target.join(".profile"), "# mine\n");
+        write(&package.join("dot-profile"), "# repo's\n");
+        (package, target)
+    }
+
+    #[test]
+    fn dotfile_names() {
+        assert_eq!(dotfile_name("dot-bashrc"), ".bashrc");
+        assert_eq!(dotfile_name("dot-config"), ".config");
+        assert_eq!(dotfile_name("dot-"), "dot-");
+        assert_eq!(dotfile_name("bashrc"), "bashrc");
+    }
+
+    #[test]
+    fn relative_paths() {
+        let path = Path::new("/nonexistent/repo/home/dot-bashrc");
+        assert_eq!(relative_path(path, Path::new("/nonexistent/home")), Path::new("../repo/home/dot-bashrc"));
+        assert_eq!(relative_path(path, Path::new("/nonexistent/repo/home")), Path::new("dot-bashrc"));
+        assert_eq!(relative_path(path, Path::new("/other")), Path::new("../nonexistent/repo/home/dot-bashrc"));
+    }
+
+    #[test]
+    fn local_ignore_file() {
+        let package = tempdir("ignore");
+        write(&package.join(IGNORE_FILE), "# comment\n\n^/README.*\nsecret.*\n");
+        let ignore = Ignore::load(&package).unwrap();
+
+        assert!(ignore.matches(Path::new(IGNORE_FILE), IGNORE_FILE));
+        assert!(ignore.matches(Path::new("README.md"), "README.md"));
+        assert!(!ignore.matches(Path::new("dot-config/README.md"), "README.md"));
+        assert!(ignore.matches(Path::new("dot-config/secret.key"), "secret.key"));
+        assert!(!ignore.matches(Path::new("dot-bashrc"), "dot-bashrc"));
+        // The local file replaces the default list.
+        assert!(!ignore.matches(Path::new(".git"), ".git"));
+
+        write(&package.join(IGNORE_FILE), "(\n");
+        assert!(Ignore::load(&package).is_err());
+    }
+
+    #[test]
+    fn default_ignore_list() {
+        let ignore = Ignore::load(&tempdir("default-ignore")).unwrap();
+        assert!(ignore.matches(Path::new(".git"), ".git"));
+        assert!(ignore.matches(Path::new("dot-bashrc~"), "dot-bashrc~"));
+        assert!(ignore.matches(Path::new("LICENSE"), "LICENSE"));
+        assert!(!ignore.matches(Path::new("dot-config/LICENSE"), "LICENSE"));
+        assert!(!ignore.matches(Path::new("dot-bashrc"), "dot-bashrc"));
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let _lock = state_lock();
+        let manifest = Manifest {
+            created_dirs: vec![PathBuf::from("/home/me/.config")],
+            links: vec![CreatedLink {
+                target: PathBuf::from("/home/me/.bashrc"),
+                source: PathBuf::from("/repo/dot-bashrc"),
+            }],
+            rendered: vec![PathBuf::from("/home/me/.gitconfig")],
+            backups: vec![BackedUp {
+                original: PathBuf::from("/home/me/.profile"),
+                backup: PathBuf::from("/state/backups/run/.profile"),
+            }],
+        };
+        let run_id = new_run_id();
+        manifest.save(&run_id).unwrap();
+
+        let loaded = Manifest::load(&run_id).unwrap();
+        assert_eq!(serde_json::to_value(&loaded).unwrap(), serde_json::to_value(&manifest).unwrap());
+        assert!(backup_runs().contains(&run_id));
+        let err = Manifest::load("no-such-run").err().unwrap();
+        assert!(err.to_string().starts_with("no stow run `no-such-run`"), "{}", err);
+    }
+
+    #[test]
+    fn plan_and_apply() {
+        let _lock = state_lock();
+        let (package, target) = fixture("apply");
+        let stow = stow_task(&package, &target);
+
+        let planned = plan(&stow).unwrap();
+        assert!(!planned.is_satisfied());
+        let conflicts: Vec<_> = planned.conflicts().map(|conflict| conflict.target.clone()).collect();
+        assert_eq!(conflicts, [target.join(".profile")]);
+        assert!(planned.conflicts().all(|conflict| conflict.adoptable));
+
+        apply(&stow, &planned, &|_| {}).unwrap();
+        assert_eq!(fs::read_link(target.join(".bashrc")).unwrap(), Path::new("../package/dot-bashrc"));
+        // A directory missing from the target is folded into one link.
+        assert_eq!(fs::read_link(target.join(".config")).unwrap(), Path::new("../package/dot-config"));
+        assert_eq!(fs::read_to_string(target.join(".greeting")).unwrap().trim_end(), "hello world");
+        assert_eq!(fs::read_to_string(target.join(".profile")).unwrap(), "# repo's\n");
+        assert!(!target.join("README.md").exists());
+        assert!(plan(&stow).unwrap().is_satisfied());
+    }
+
+    #[test]
+    fn unstow_removes_what_stow_created() {
+        let _lock = state_lock();
+        let (package, target) = fixture("unstow");
+        let stow = stow_task(&package, &target);
+        apply(&stow, &plan(&stow).unwrap(), &|_| {}).unwrap();
+        write(&target.join(".unrelated"), "");
+
+        let planned = unstow_plan(&stow).unwrap();
+        let mut removed = planned.remove.clone();
+        removed.sort();
+        let expected: Vec<PathBuf> =
+            [".bashrc", ".config", ".greeting", ".profile"].iter().map(|name| target.join(name)).collect();
+        assert_eq!(removed, expected);
+
+        unstow(&planned, &|_| {}).unwrap();
+        for path in &expected {
+            assert!(fs::symlink_metadata(path).is_err(), "{} is still there", path.display());
+        }
+        assert!(target.join(".unrelated").exists());
+        assert!(package.join("dot-bashrc").exists());
+        assert!(unstow_plan(&stow).unwrap().is_satisfied());
+    }
+
+    #[test]
+    fn edited_templates_are_left_alone() {
+        let _lock = state_lock();
+        let (package, target) = fixture("edited");
+        let stow = stow_task(&package, &target);
+        apply(&stow, &plan(&stow).unwrap(), &|_| {}).unwrap();
+        let greeting = target.join(".greeting");
+        fs::write(&greeting, "hello by hand\n").unwrap();
+
+        let planned = plan(&stow).unwrap();
+        let conflicts: Vec<_> = planned.conflicts().map(|c| (c.target.clone(), c.reason.as_str(), c.adoptable)).collect();
+        assert_eq!(conflicts, [(greeting.clone(), "was edited after it was rendered", false)]);
+        let states = link_states(&stow).unwrap();
+        assert!(states.iter().any(|(path, state)| path == &greeting && *state == LinkState::Edited));
+
+        let planned = unstow_plan(&stow).unwrap();
+        assert!(!planned.remove.contains(&greeting));
+        unstow(&planned, &|_| {}).unwrap();
+        assert_eq!(fs::read_to_string(&greeting).unwrap(), "hello by hand\n");
+
+        // A template change re-renders an untouched file.
+        let stow = stow_task(&package, &target);
+        fs::remove_file(&greeting).unwrap();
+        apply(&stow, &plan(&stow).unwrap(), &|_| {}).unwrap();
+        write(&package.join("dot-greeting.tmpl"), "hi {{ name }}\n");
+        let planned = plan(&stow).unwrap();
+        assert!(planned.actions.iter().any(|action| matches!(action, Action::Render { target, .. } if target == &greeting)));
+    }
+
+    #[test]
+    fn restore_undoes_a_run() {
+        let _lock = state_lock();
+        let (package, target) = fixture("restore");
+        let stow = stow_task(&package, &target);
+        apply(&stow, &plan(&stow).unwrap(), &|_| {}).unwrap();
+        let run_id = backup_runs().into_iter().last().unwrap();
+
+        restore(&run_id, &|_| {}).unwrap();
+        assert_eq!(fs::read_to_string(target.join(".profile")).unwrap(), "# mine\n");
+        for name in [".bashrc", ".config", ".greeting"] {
+            assert!(fs::symlink_metadata(target.join(name)).is_err(), "{} is still there", name);
+        }
+        assert!(package.join("dot-profile").exists());
+    }
+
+    #[test]
+    fn package_and_target_must_not_overlap() {
+        let _lock = state_lock();
+        let (package, _) = fixture("overlap");
+        let overlaps = |target: &Path| {
+            let stow = stow_task(&package, target);
+            for result in [plan(&stow).err(), unstow_plan(&stow).err()] {
+                let err = result.expect("overlap not detected").to_string();
+                assert!(err.contains("overlap"), "{}", err);
+            }
+        };
+        overlaps(&package);
+        overlaps(&package.join("dot-config"));
+
+        // An entry that would be linked over the package's own parent.
+        let root = package.parent().unwrap();
+        write(&package.join("package/file"), "");
+        overlaps(root);
+    }
+
+    #[test]
+    fn package_inside_target_is_fine() {
+        let _lock = state_lock();
+        let (package, _) = fixture("inside");
+        let stow = stow_task(&package, package.parent().unwrap());
+        assert!(plan(&stow).is_ok());
+        assert!(unstow_plan(&stow).is_ok());
+    }
+}
@@ -0,0 +1,532 @@
+use crate::config::{
+    AptGroup, AptRepositories, CargoGroup, FlatpakGroup, HomebrewGroup, PackageGroup, Repositories, ShellCommand,
+    StowTask, Task, TaskKind,
+};
+use crate::distro;
+use crate::exec::Executor;
+use crate::privilege;
+use crate::stow;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use std::fmt;
+use std::process::{Command, Stdio};
+
+/// A single command a task runs, in the exact form it is executed.
+pub struct PlannedCommand {
+    pub line: String,
+    pub privileged: bool,
+}
+
+impl PlannedCommand {
+    fn user(line: String) -> Self {
+        Self { line, privileged: false }
+    }
+
+    fn root(line: String) -> Self {
+        Self { line, privileged: true }
+    }
+}
+
+/// Shows the command as [`Executor`] runs it: privileged lines go through
+/// `sudo -n -- sh -c` unless dot-setup already runs as root.
+impl fmt::Display for PlannedCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.privileged && !privilege::is_root() {
+            write!(f, "sudo -n -- sh -c '{}'", self.line.replace('\'', "'\\''"))
+        } else {
+            f.write_str(&self.line)
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PackageState {
+    Installed,
+    Missing,
+    /// The package is given as a URL or the package manager isn't available
+    /// yet, so there's no way to tell before running.
+    Unknown,
+}
+
+/// Outcome of a task's check phase.
+pub enum Check {
+    /// Everything the task would do is already in place.
+    Satisfied,
+    /// The task still has work to do; carries a copy narrowed down to the
+    /// packages that aren't installed yet.
+    Pending(Box<Task>),
+}
+
+/// Decides whether `task` can be skipped, using its `check` command when it
+/// has one and the package manager's view (`rpm -q`, `flatpak info`,
+/// `brew list`, `cargo install --list`) otherwise. Stow tasks are satisfied
+/// when every link is already in place; dnf groups with `upgrade` never are.
+pub fn check_task(task: &Task) -> Check {
+    let states = package_states(task);
+    let missing = |name: &str| {
+        states
+            .iter()
+            .any(|(pkg, state)| pkg == name && *state != PackageState::Installed)
+    };
+
+    let satisfied = match (&task.check, &task.kind) {
+        (Some(check), _) => succeeded(check),
+        // There's no cheap way to tell whether an upgrade would change
+        // anything, so a group that upgrades always runs.
+        (None, TaskKind::Dnf(PackageGroup { upgrade: true, .. })) => false,
+        (None, TaskKind::Dnf(PackageGroup { services, .. }) | TaskKind::Apt(AptGroup { services, .. })) => {
+            states.iter().all(|(_, state)| *state == PackageState::Installed)
+                && services.iter().all(|service| service_enabled(service))
+        }
+        // A `.repo` file can't be checked from its URL alone.
+        (None, TaskKind::DnfRepos(repos)) => {
+            repos.repo_files.is_empty() && states.iter().all(|(_, state)| *state == PackageState::Installed)
+        }
+        (None, TaskKind::AptRepos(_) | TaskKind::Flatpak(_) | TaskKind::Brew(_) | TaskKind::Cargo(_)) => {
+            states.iter().all(|(_, state)| *state == PackageState::Installed)
+        }
+        (None, TaskKind::Stow(task)) => stow::plan(task).is_ok_and(|plan| plan.is_satisfied()),
+        (None, TaskKind::Unstow(task)) => stow::unstow_plan(task).is_ok_and(|plan| plan.is_satisfied()),
+        (None, TaskKind::Shell(_)) => false,
+    };
+    if satisfied {
+        return Check::Satisfied;
+    }
+
+    let mut pending = task.clone();
+    pending.kind.retain_items(missing);
+    Check::Pending(Box::new(pending))
+}
+
+pub fn run_task(exec: &Executor, task: &Task) -> Result<()> {
+    if let TaskKind::Stow(task) = &task.kind {
+        let plan = stow::plan(task)?;
+        return stow::apply(task, &plan, &|line| exec.report(line));
+    }
+    if let TaskKind::Unstow(task) = &task.kind {
+        let plan = stow::unstow_plan(task)?;
+        return stow::unstow(&plan, &|line| exec.report(line));
+    }
+    if let TaskKind::AptRepos(repos) = &task.kind {
+        codename(repos)?;
+    }
+
+    for cmd in plan_task(task) {
+        if cmd.privileged {
+            exec.run_privileged(&cmd.line)?;
+        } else {
+            exec.run(&cmd.line)?;
+        }
+    }
+    Ok(())
+}
+
+/// The commands `run_task` would execute for `task`, in order. Stow tasks
+/// run in-process; theirs are the shell equivalent of the link plan.
+pub fn plan_task(task: &Task) -> Vec<PlannedCommand> {
+    match &task.kind {
+        TaskKind::DnfRepos(repos) => plan_repos(repos),
+        TaskKind::Dnf(group) => plan_dnf(group),
+        TaskKind::AptRepos(repos) => plan_apt_repos(repos),
+        TaskKind::Apt(group) => plan_apt(group),
+        TaskKind::Flatpak(group) => plan_flatpak(group),
+        TaskKind::Brew(group) => plan_homebrew(group),
+        TaskKind::Cargo(group) => plan_cargo(group),
+        TaskKind::Shell(shell) => plan_shell(shell),
+        TaskKind::Stow(stow) => plan_stow_task(stow),
+        TaskKind::Unstow(stow) => plan_unstow_task(stow),
+    }
+}
+
+/// A human readable dry-run report for `task`: the commands it would run
+/// and which of its packages are already installed.
+pub fn describe_plan(task: &Task) -> Vec<String> {
+    let mut lines = Vec::new();
+    let pending = match check_task(task) {
+        Check::Satisfied => {
+            lines.push("  already done, nothing to run".to_string());
+            return lines;
+        }
+        Check::Pending(pending) => pending,
+    };
+    lines.extend(plan_task(&pending).iter().map(|cmd| format!("  $ {}", cmd)));
+    match &pending.kind {
+        TaskKind::Stow(task) => match stow::plan(task) {
+            Ok(plan) => lines.extend(stow::describe(task, &plan)),
+            Err(err) => lines.push(format!("  error: {}", err)),
+        },
+        TaskKind::Unstow(task) => match stow::unstow_plan(task) {
+            Ok(plan) => lines.extend(
+                plan.kept
+                    .iter()
+                    .map(|(path, reason)| format!("  left alone: {} {}", path.display(), reason)),
+            ),
+            Err(err) => lines.push(format!("  error: {}", err)),
+        },
+        TaskKind::AptRepos(repos) => {
+            if let Err(err) = codename(repos) {
+                lines.push(format!("  error: {}", err));
+            }
+        }
+        _ => {}
+    }
+
+    let states = package_states(task);
+    let with_state = |wanted: PackageState| -> Vec<&str> {
+        states
+            .iter()
+            .filter(|(_, state)| *state == wanted)
+            .map(|(name, _)| name.as_str())
+            .collect()
+    };
+    for (label, state) in [
+        ("already installed", PackageState::Installed),
+        ("would install", PackageState::Missing),
+        ("unknown", PackageState::Unknown),
+    ] {
+        let names = with_state(state);
+        if !names.is_empty() {
+            lines.push(format!("  {}: {}", label, names.join(", ")));
+        }
+    }
+
+    lines
+}
+
+/// Queries whether each package a task installs is already present.
+pub fn package_states(task: &Task) -> Vec<(String, PackageState)> {
+    match &task.kind {
+        TaskKind::DnfRepos(repos) => repos
+            .bootstrap
+            .iter()
+            .map(|repo| (repo.package.clone(), rpm_state(&repo.package)))
+            .chain(repos.release_packages.iter().map(|url| (url.clone(), PackageState::Unknown)))
+            .collect(),
+        TaskKind::Dnf(group) => group
+            .packages
+            .iter()
+            .map(|pkg| (pkg.clone(), rpm_state(pkg)))
+            .collect(),
+        TaskKind::AptRepos(repos) => repos
+            .repositories
+            .iter()
+            .map(|repo| (repo.name.clone(), apt_source_state(&repo.name)))
+            .collect(),
+        TaskKind::Apt(group) => group
+            .packages
+            .iter()
+            .map(|pkg| (pkg.clone(), dpkg_state(pkg)))
+            .collect(),
+        TaskKind::Flatpak(group) => group
+            .apps
+            .iter()
+            .map(|app| (app.clone(), flatpak_state(app)))
+            .collect(),
+        TaskKind::Brew(group) => group
+            .packages
+            .iter()
+            .map(|pkg| (pkg.clone(), brew_state(group, pkg)))
+            .collect(),
+        TaskKind::Cargo(group) => {
+            let installed = cargo_installed();
+            group
+                .packages
+                .iter()
+                .map(|pkg| {
+                    let state = match &installed {
+                        Some(installed) if installed.contains(pkg) => PackageState::Installed,
+                        Some(_) => PackageState::Missing,
+                        None => PackageState::Unknown,
+                    };
+                    (pkg.clone(), state)
+                })
+                .collect()
+        }
+        TaskKind::Shell(_) | TaskKind::Stow(_) | TaskKind::Unstow(_) => Vec::new(),
+    }
+}
+
+fn plan_repos(repos: &Repositories) -> Vec<PlannedCommand> {
+    let mut cmds = Vec::new();
+
+    if !repos.release_packages.is_empty() {
+        cmds.push(PlannedCommand::root(format!("dnf install -y {}", repos.release_packages.join(" "))));
+    }
+
+    for repo_file in &repos.repo_files {
+        cmds.push(PlannedCommand::root(format!(
+            "dnf config-manager addrepo --overwrite --from-repofile {}",
+            repo_file
+        )));
+    }
+
+    for repo in &repos.bootstrap {
+        cmds.push(PlannedCommand::root(format!(
+            "if ! rpm -q {pkg} >/dev/null 2>&1; then dnf install -y --nogpgcheck --repofrompath '{},{}' {pkg}; fi",
+            repo.name,
+            repo.baseurl,
+            pkg = repo.package
+        )));
+    }
+
+    cmds
+}
+
+fn plan_dnf(group: &PackageGroup) -> Vec<PlannedCommand> {
+    let mut cmds = Vec::new();
+
+    if group.upgrade {
+        cmds.push(PlannedCommand::root("dnf upgrade -y".to_string()));
+    }
+
+    if !group.packages.is_empty() {
+        cmds.push(PlannedCommand::root(format!("dnf install -y {}", group.packages.join(" "))));
+    }
+
+    for service in &group.services {
+        cmds.push(PlannedCommand::root(format!("systemctl enable --now {}", service)));
+    }
+
+    cmds
+}
+
+/// The host's `VERSION_CODENAME`. Fails when a repository uses
+/// `{codename}` and os-release doesn't set one, instead of writing a source
+/// line with an empty suite.
+fn codename(repos: &AptRepositories) -> Result<String> {
+    let codename = distro::detect().version_codename.unwrap_or_default();
+    let uses_codename = |s: &String| s.contains("{codename}");
+    let repo = repos
+        .repositories
+        .iter()
+        .find(|repo| uses_codename(&repo.uri) || repo.suites.iter().any(uses_codename));
+    match repo {
+        Some(repo) if codename.is_empty() => Err(eyre!(
+            "apt repository `{}` uses {{codename}}, but /etc/os-release has no VERSION_CODENAME; \
+             list its suites explicitly instead",
+            repo.name
+        )),
+        _ => Ok(codename),
+    }
+}
+
+fn plan_apt_repos(repos: &AptRepositories) -> Vec<PlannedCommand> {
+    // Shown as is in a dry run; `run_task` refuses to run without it.
+    let codename = codename(repos).unwrap_or_else(|_| "{codename}".to_string());
+    let mut cmds = Vec::new();
+
+    if repos.repositories.iter().any(|repo| repo.key_url.is_some()) {
+        cmds.push(PlannedCommand::root("install -d -m 0755 /etc/apt/keyrings".to_string()));
+    }
+
+    for repo in &repos.repositories {
+        let mut options = Vec::new();
+        if let Some(arch) = &repo.arch {
+            options.push(format!("arch={}", arch));
+        }
+        if let Some(key_url) = &repo.key_url {
+            let keyring = format!("/etc/apt/keyrings/{}.asc", repo.name);
+            cmds.push(PlannedCommand::root(format!(
+                "curl -fsSL {} -o {keyring} && chmod a+r {keyring}",
+                key_url,
+                keyring = keyring
+            )));
+            options.push(format!("signed-by={}", keyring));
+        }
+
+        let options = if options.is_empty() { String::new() } else { format!("[{}] ", options.join(" ")) };
+        let line = format!(
+            "deb {}{} {} {}",
+            options,
+            repo.uri.replace("{codename}", &codename),
+            repo.suites.join(" ").replace("{codename}", &codename),
+            repo.components.join(" ")
+        );
+        cmds.push(PlannedCommand::root(format!(
+            "echo '{}' > /etc/apt/sources.list.d/{}.list",
+            line, repo.name
+        )));
+    }
+
+    cmds
+}
+
+fn plan_apt(group: &AptGroup) -> Vec<PlannedCommand> {
+    let mut cmds = Vec::new();
+
+    if group.update_cache {
+        cmds.push(PlannedCommand::root("apt-get update".to_string()));
+    }
+
+    if !group.packages.is_empty() {
+        cmds.push(PlannedCommand::root(format!(
+            "DEBIAN_FRONTEND=noninteractive apt-get install -y {}",
+            group.packages.join(" ")
+        )));
+    }
+
+    for service in &group.services {
+        cmds.push(PlannedCommand::root(format!("systemctl enable --now {}", service)));
+    }
+
+    cmds
+}
+
+fn plan_flatpak(group: &FlatpakGroup) -> Vec<PlannedCommand> {
+    let mut cmds = vec![PlannedCommand::user(format!(
+        "flatpak remote-add --if-not-exists {} {}",
+        group.remote, group.remote_url
+    ))];
+    if !group.apps.is_empty() {
+        cmds.push(PlannedCommand::user(format!("flatpak install -y {} {}", group.remote, group.apps.join(" "))));
+    }
+    cmds
+}
+
+fn plan_homebrew(group: &HomebrewGroup) -> Vec<PlannedCommand> {
+    let mut cmds = vec![PlannedCommand::user(format!(
+        "eval \"$({} 2>/dev/null)\"; command -v brew >/dev/null || /bin/bash -c \"$(curl -fsSL {})\"",
+        group.shell_init, group.install_script
+    ))];
+    if !group.packages.is_empty() {
+        cmds.push(PlannedCommand::user(format!(
+            "eval \"$({})\" && brew install {}",
+            group.shell_init,
+            group.packages.join(" ")
+        )));
+    }
+    cmds
+}
+
+fn plan_cargo(group: &CargoGroup) -> Vec<PlannedCommand> {
+    if group.packages.is_empty() {
+        return Vec::new();
+    }
+    vec![PlannedCommand::user(format!("cargo install {}", group.packages.join(" ")))]
+}
+
+fn plan_shell(shell: &ShellCommand) -> Vec<PlannedCommand> {
+    vec![PlannedCommand { line: shell.command.clone(), privileged: shell.sudo }]
+}
+
+fn plan_stow_task(task: &StowTask) -> Vec<PlannedCommand> {
+    let Ok(plan) = stow::plan(task) else {
+        return Vec::new();
+    };
+    let mut cmds: Vec<PlannedCommand> = plan
+        .create_dirs
+        .iter()
+        .map(|dir| PlannedCommand::user(format!("mkdir -p {}", dir.display())))
+        .collect();
+    for action in &plan.actions {
+        if let stow::Action::Link { source, target } = action {
+            cmds.push(PlannedCommand::user(format!("ln -s {} {}", source.display(), target.display())));
+        }
+    }
+    cmds
+}
+
+fn plan_unstow_task(task: &StowTask) -> Vec<PlannedCommand> {
+    let Ok(plan) = stow::unstow_plan(task) else {
+        return Vec::new();
+    };
+    let mut cmds: Vec<PlannedCommand> = plan
+        .remove
+        .iter()
+        .map(|link| PlannedCommand::user(format!("rm {}", link.display())))
+        .collect();
+    cmds.extend(
+        plan.dirs
+            .iter()
+            .filter(|dir| dir.exists())
+            .map(|dir| PlannedCommand::user(format!("rmdir --ignore-fail-on-non-empty {}", dir.display()))),
+    );
+    cmds
+}
+
+/// Runs a query command quietly; `None` if it could not be started at all.
+fn query(cmd: &str) -> Option<std::process::Output> {
+    Command::new("sh")
+        .args(["-c", cmd])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+}
+
+fn succeeded(cmd: &str) -> bool {
+    query(cmd).is_some_and(|output| output.status.success())
+}
+
+/// Maps a query's exit status to a package state: 0 means installed.
+fn install_state(cmd: &str) -> PackageState {
+    match query(cmd) {
+        Some(output) if output.status.success() => PackageState::Installed,
+        Some(_) => PackageState::Missing,
+        None => PackageState::Unknown,
+    }
+}
+
+pub fn has_command(name: &str) -> bool {
+    succeeded(&format!("command -v {} >/dev/null", name))
+}
+
+fn service_enabled(service: &str) -> bool {
+    succeeded(&format!("systemctl is-enabled --quiet {}", service))
+}
+
+fn rpm_state(pkg: &str) -> PackageState {
+    if pkg.contains("://") || !has_command("rpm") {
+        return PackageState::Unknown;
+    }
+    install_state(&format!("rpm -q --quiet {}", pkg))
+}
+
+fn dpkg_state(pkg: &str) -> PackageState {
+    if !has_command("dpkg-query") {
+        return PackageState::Unknown;
+    }
+    match query(&format!("dpkg-query -W -f='${{Status}}' {}", pkg)) {
+        Some(output) if String::from_utf8_lossy(&output.stdout).ends_with("install ok installed") => {
+            PackageState::Installed
+        }
+        Some(_) => PackageState::Missing,
+        None => PackageState::Unknown,
+    }
+}
+
+fn apt_source_state(name: &str) -> PackageState {
+    install_state(&format!("test -f /etc/apt/sources.list.d/{}.list", name))
+}
+
+fn flatpak_state(app: &str) -> PackageState {
+    if !has_command("flatpak") {
+        return PackageState::Unknown;
+    }
+    install_state(&format!("flatpak info {} >/dev/null", app))
+}
+
+fn brew_state(group: &HomebrewGroup, pkg: &str) -> PackageState {
+    // `brew` isn't on PATH until its shellenv has been evaluated.
+    if !succeeded(&format!("eval \"$({})\" && command -v brew >/dev/null", group.shell_init)) {
+        return PackageState::Missing;
+    }
+    let name = pkg.rsplit('/').next().unwrap_or(pkg);
+    install_state(&format!("eval \"$({})\" && brew list --versions {} >/dev/null", group.shell_init, name))
+}
+
+/// Names of the crates `cargo install --list` reports.
+fn cargo_installed() -> Option<Vec<String>> {
+    let output = query("cargo install --list")?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|line| !line.starts_with(char::is_whitespace))
+            .filter_map(|line| line.split_whitespace().next())
+            .map(str::to_string)
+            .collect(),
+    )
+}
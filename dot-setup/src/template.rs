@@ -0,0 +1,186 @@
+//! `*.tmpl` dotfiles: rendered with minijinja from the `vars` in
+//! config.toml plus facts about the host, and written as real files.
+
+use crate::distro;
+use crate::state;
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::Result;
+use minijinja::{Environment, UndefinedBehavior, Value};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Package entries with this suffix are templates; the target drops it.
+pub const SUFFIX: &str = ".tmpl";
+
+/// Facts about this machine, available to templates as `host.*`.
+pub fn host_facts() -> BTreeMap<&'static str, String> {
+    let distro = distro::detect();
+    let var = |name: &str| env::var(name).unwrap_or_default();
+    BTreeMap::from([
+        ("hostname", distro::hostname()),
+        ("distro", distro.id),
+        ("distro_name", distro.pretty_name),
+        ("desktop", var("XDG_CURRENT_DESKTOP")),
+        ("session_type", var("XDG_SESSION_TYPE")),
+        ("user", var("USER")),
+    ])
+}
+
+/// Renders templates against one set of variables. Referencing a variable
+/// that isn't defined is an error rather than an empty string.
+pub struct Renderer {
+    env: Environment<'static>,
+    context: Value,
+}
+
+impl Renderer {
+    pub fn new(vars: &toml::Table) -> Self {
+        let mut env = Environment::new();
+        env.set_undefined_behavior(UndefinedBehavior::Strict);
+        env.set_keep_trailing_newline(true);
+
+        let mut context = BTreeMap::new();
+        for (name, value) in vars {
+            context.insert(name.clone(), Value::from_serialize(value));
+        }
+        context.insert("host".to_string(), Value::from_serialize(host_facts()));
+        Self { env, context: Value::from(context) }
+    }
+
+    pub fn render(&self, path: &Path) -> Result<String> {
+        let source = fs::read_to_string(path).wrap_err_with(|| format!("could not read {}", path.display()))?;
+        self.env
+            .render_str(&source, &self.context)
+            .map_err(|err| eyre!("{}: {:#}", path.display(), err))
+    }
+}
+
+/// A cheap, stable fingerprint (64-bit FNV-1a) of rendered output, used to
+/// tell whether a file was edited after dot-setup wrote it.
+pub fn fingerprint(content: &[u8]) -> String {
+    let hash = content
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325u64, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3));
+    format!("{:016x}", hash)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RenderRecord {
+    pub source: PathBuf,
+    pub fingerprint: String,
+}
+
+/// Every file dot-setup has rendered, by target path, kept in
+/// `rendered.json` in the state directory.
+#[derive(Serialize, Deserialize, Default)]
+pub struct RenderedFiles {
+    #[serde(default)]
+    pub files: BTreeMap<PathBuf, RenderRecord>,
+}
+
+impl RenderedFiles {
+    fn path() -> PathBuf {
+        state::state_dir().join("rendered.json")
+    }
+
+    pub fn load() -> Self {
+        fs::read_to_string(Self::path())
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Whether `target` still holds exactly what dot-setup last wrote there.
+    pub fn is_untouched(&self, target: &Path) -> bool {
+        let Some(record) = self.files.get(target) else { return false };
+        fs::read(target).is_ok_and(|content| fingerprint(&content) == record.fingerprint)
+    }
+
+    pub fn record(&mut self, target: &Path, source: &Path, content: &str) {
+        let record = RenderRecord { source: source.to_path_buf(), fingerprint: fingerprint(content.as_bytes()) };
+        self.files.insert(target.to_path_buf(), record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tempdir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("dot-setup-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn render(dir: &Path, vars: &str, template: &str) -> Result<String> {
+        let path = dir.join("dot-file.tmpl");
+        fs::write(&path, template).unwrap();
+        Renderer::new(&toml::from_str(vars).unwrap()).render(&path)
+    }
+
+    #[test]
+    fn renders_vars_and_keeps_the_trailing_newline() {
+        let dir = tempdir("render");
+        let output = render(&dir, "font_size = 11\nfonts = [\"a\", \"b\"]", "size={{ font_size }} {{ fonts | join(\",\") }}\n");
+        assert_eq!(output.unwrap(), "size=11 a,b\n");
+    }
+
+    #[test]
+    fn undefined_variables_are_errors() {
+        let dir = tempdir("undefined");
+        let err = render(&dir, "", "size={{ font_size }}\n").unwrap_err().to_string();
+        assert!(err.starts_with(&dir.join("dot-file.tmpl").display().to_string()), "{}", err);
+        assert!(err.contains("undefined"), "{}", err);
+        assert!(render(&dir, "", "{{ host.nope }}").is_err());
+        assert!(render(&dir, "", "{% if missing %}x{% endif %}").is_err());
+    }
+
+    #[test]
+    fn host_facts() {
+        let dir = tempdir("host");
+        let output = render(&dir, "", "{{ host.hostname }}|{{ host.distro }}|{{ host.distro_name }}").unwrap();
+        let distro = distro::detect();
+        assert_eq!(output, format!("{}|{}|{}", distro::hostname(), distro.id, distro.pretty_name));
+        for fact in ["desktop", "session_type", "user"] {
+            assert!(render(&dir, "", &format!("{{{{ host.{} }}}}", fact)).is_ok(), "host.{}", fact);
+        }
+    }
+
+    #[test]
+    fn fingerprints_are_fnv1a() {
+        assert_eq!(fingerprint(b""), "cbf29ce484222325");
+        assert_eq!(fingerprint(b"a"), "af63dc4c8601ec8c");
+        assert_ne!(fingerprint(b"size=11\n"), fingerprint(b"size=12\n"));
+    }
+
+    #[test]
+    fn hand_edits_are_noticed() {
+        let dir = tempdir("untouched");
+        let (source, target) = (dir.join("dot-file.tmpl"), dir.join(".file"));
+        let mut rendered = RenderedFiles::default();
+        assert!(!rendered.is_untouched(&target));
+
+        fs::write(&target, "size=11\n").unwrap();
+        assert!(!rendered.is_untouched(&target), "a file dot-setup never wrote");
+        rendered.record(&target, &source, "size=11\n");
+        assert!(rendered.is_untouched(&target));
+
+        fs::write(&target, "size=14\n").unwrap();
+        assert!(!rendered.is_untouched(&target));
+        fs::remove_file(&target).unwrap();
+        assert!(!rendered.is_untouched(&target));
+    }
+}